@@ -1,12 +1,8 @@
 use simple_http_server::{App, Result, request::Request, response::Response};
 
-fn echo(request: &Request, response: Response) -> Response {
-    let echo_value = request.path_params.get("echo");
-    if let Some(value) = echo_value {
-        response.set_body(value).set_status(200)
-    } else {
-        response.set_body("no value?").set_status(500)
-    }
+fn echo(request: &Request, _response: Response) -> Option<String> {
+    // answered with a 404 when there is nothing to echo
+    request.path_params.get("echo").cloned()
 }
 
 pub fn main() -> Result<()> {
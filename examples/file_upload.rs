@@ -2,34 +2,21 @@ use std::{fs::File, io::Write, path::PathBuf};
 
 use simple_http_server::{App, Result, request::Request, response::Response};
 
-fn upload_file(request: &Request, response: Response) -> Response {
+fn upload_file(
+    request: &Request,
+    response: Response,
+) -> std::result::Result<Response, (u16, String)> {
     let folder_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples/gallery/images");
-    let file_path = folder_path.join(request.path_params.get("filename").unwrap());
-    let file = File::create(file_path);
-
-    if let Err(create_error) = file {
-        return response
-            .set_status(500)
-            .set_body(format!("{create_error}").as_str());
-    }
-    let mut file = file.unwrap();
-
-    if let Err(err) = file.write_all(&request.body) {
-        return response
-            .set_status(500)
-            .set_body(format!("Could not write: {err}").as_str());
-    }
+    let filename = request.path_params.get("filename").unwrap();
+    let mut file =
+        File::create(folder_path.join(filename)).map_err(|err| (500, err.to_string()))?;
+    file.write_all(&request.body)
+        .map_err(|err| (500, format!("Could not write: {err}")))?;
     let _ = file.flush();
 
-    response
-        .add_header(
-            "Location",
-            &format!(
-                "/gallery/images/{}",
-                request.path_params.get("filename").unwrap()
-            ),
-        )
-        .set_status(201)
+    Ok(response
+        .add_header("Location", &format!("/gallery/images/{filename}"))
+        .set_status(201))
 }
 
 fn list_files(_request: &Request, response: Response) -> Response {
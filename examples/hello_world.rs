@@ -1,7 +1,7 @@
 use simple_http_server::{App, Result, request::Request, response::Response};
 
-fn say_hello(_request: &Request, response: Response) -> Response {
-    response.set_body("Hello, World").set_status(200)
+fn say_hello(_request: &Request, _response: Response) -> &'static str {
+    "Hello, World"
 }
 
 pub fn main() -> Result<()> {
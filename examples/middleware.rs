@@ -0,0 +1,41 @@
+use std::time::Instant;
+
+use simple_http_server::{
+    App, Result,
+    middleware::Next,
+    request::{Request, RequestMethod},
+    response::Response,
+};
+
+fn hello(request: &Request, response: Response) -> Response {
+    let user = request.header("X-User").unwrap_or("stranger");
+    response.set_body(&format!("Hello, {user}")).set_status(200)
+}
+
+// Adds a header with the time it took to produce the response.
+fn timing(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let response = next.run(request);
+    let elapsed = format!("app;dur={}", started.elapsed().as_micros());
+    response.add_header("Server-Timing", &elapsed)
+}
+
+// Turns away requests without the right token and tells the handler who is calling.
+fn require_token(mut request: Request, next: Next) -> Response {
+    if request.header("Authorization") != Some("Bearer letmein") {
+        return Response::new().set_status(401);
+    }
+    request.headers.insert("X-User".into(), "admin".into());
+    next.run(request)
+}
+
+pub fn main() -> Result<()> {
+    App::new()
+        .set_listen_ip("0.0.0.0")
+        .set_port(8080)
+        .get("/", hello)
+        .get("/admin", hello)
+        .wrap(timing)
+        .wrap_route(RequestMethod::Get, "/admin", require_token)
+        .run()
+}
@@ -4,21 +4,18 @@
 // first define the specific routes and then add the route that matches on path params.
 use simple_http_server::{App, Result, request::Request, response::Response};
 
-fn echo(request: &Request, response: Response) -> Response {
-    let param = request.path_params.get("fruit");
-    if let Some(fruit) = param {
-        response
-            .set_body(format!("You should eat fruits everyday. {} is delicious.", fruit).as_str())
-            .set_status(200)
-    } else {
-        response.set_body("no value?").set_status(500)
+fn echo(request: &Request, _response: Response) -> (u16, String) {
+    match request.path_params.get("fruit") {
+        Some(fruit) => (
+            200,
+            format!("You should eat fruits everyday. {fruit} is delicious."),
+        ),
+        None => (500, "no value?".to_string()),
     }
 }
 
-fn apple(_request: &Request, response: Response) -> Response {
-    response
-        .set_body("An apple a day keeps the doctor away!")
-        .set_status(200)
+fn apple(_request: &Request, _response: Response) -> &'static str {
+    "An apple a day keeps the doctor away!"
 }
 
 pub fn main() -> Result<()> {
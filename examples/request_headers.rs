@@ -1,13 +1,7 @@
 use simple_http_server::{App, Result, request::Request, response::Response};
 
-fn user_agent(request: &Request, response: Response) -> Response {
-    let user_agent = request.headers.get("User-Agent");
-    let mut response = response.set_status(200);
-    if let Some(user_agent) = user_agent {
-        response = response.set_body(user_agent);
-    }
-
-    response
+fn user_agent(request: &Request, _response: Response) -> String {
+    request.header("User-Agent").unwrap_or_default().to_string()
 }
 
 pub fn main() -> Result<()> {
@@ -0,0 +1,55 @@
+use std::{thread, time::Duration};
+
+use simple_http_server::{App, Result, request::Request, response::Response, sse::Event};
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<body>
+    <ul id="ticks"></ul>
+    <script>
+        const source = new EventSource("/ticks");
+        source.addEventListener("tick", (event) => {
+            const item = document.createElement("li");
+            item.textContent = event.data;
+            document.getElementById("ticks").appendChild(item);
+        });
+    </script>
+</body>
+</html>"#;
+
+fn page(_request: &Request, response: Response) -> Response {
+    response
+        .add_header("Content-Type", "text/html")
+        .set_body(PAGE)
+        .set_status(200)
+}
+
+fn ticks(request: &Request, response: Response) -> Response {
+    response.sse(request, |events| {
+        // a reconnecting browser continues where it left off
+        let mut id = events
+            .last_event_id()
+            .and_then(|id| id.parse::<u64>().ok())
+            .unwrap_or(0);
+        loop {
+            id += 1;
+            let event = Event::new(&format!("tick number {id}"))
+                .set_event("tick")
+                .set_id(&id.to_string());
+            if events.send(event).is_err() {
+                println!("Client disconnected, the last tick sent was {}", id - 1);
+                return;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    })
+}
+
+pub fn main() -> Result<()> {
+    App::new()
+        .set_listen_ip("0.0.0.0")
+        .set_port(8080)
+        .get("/", page)
+        .get("/ticks", ticks)
+        .run()
+}
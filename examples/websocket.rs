@@ -0,0 +1,60 @@
+use simple_http_server::{
+    App, Result,
+    request::Request,
+    response::Response,
+    websocket::{Message, WebSocket, close_code},
+};
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<body>
+    <input id="message" placeholder="Say something">
+    <ul id="log"></ul>
+    <script>
+        const socket = new WebSocket(`ws://${location.host}/echo`);
+        socket.onmessage = (event) => {
+            const item = document.createElement("li");
+            item.textContent = event.data;
+            document.getElementById("log").appendChild(item);
+        };
+        document.getElementById("message").onchange = (event) => {
+            socket.send(event.target.value);
+            event.target.value = "";
+        };
+    </script>
+</body>
+</html>"#;
+
+fn page(_request: &Request, response: Response) -> Response {
+    response
+        .add_header("Content-Type", "text/html")
+        .set_body(PAGE)
+        .set_status(200)
+}
+
+fn echo(_request: &Request, mut socket: WebSocket) {
+    while let Ok(message) = socket.recv() {
+        let result = match message {
+            Message::Text(text) if text == "bye" => {
+                let _ = socket.close(close_code::NORMAL, "see you");
+                return;
+            }
+            Message::Text(text) => socket.send_text(&format!("echo: {text}")),
+            Message::Binary(data) => socket.send_binary(&data),
+            Message::Close(_) => return,
+            _ => Ok(()),
+        };
+        if result.is_err() {
+            return;
+        }
+    }
+}
+
+pub fn main() -> Result<()> {
+    App::new()
+        .set_listen_ip("0.0.0.0")
+        .set_port(8080)
+        .get("/", page)
+        .ws("/echo", echo)
+        .run()
+}
@@ -0,0 +1,98 @@
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 with padding, RFC 4648 section 4.
+pub(crate) fn encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bytes = [
+            group[0],
+            *group.get(1).unwrap_or(&0),
+            *group.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= group.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes standard base64 with padding, `None` if `text` isn't valid base64.
+pub(crate) fn decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(text.len() / 4 * 3);
+    for (index, group) in text.chunks(4).enumerate() {
+        let is_last = index == text.len() / 4 - 1;
+        let padding = group.iter().rev().take_while(|&&byte| byte == b'=').count();
+        if padding > 2 || (padding > 0 && !is_last) {
+            return None;
+        }
+        let mut bits = 0u32;
+        for &byte in &group[..4 - padding] {
+            let value = ALPHABET.iter().position(|&symbol| symbol == byte)?;
+            bits = bits << 6 | value as u32;
+        }
+        bits <<= 6 * padding;
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+/// Decodes the URL and filename safe alphabet without padding, RFC 4648 section 5.
+pub(crate) fn decode_url(text: &str) -> Option<Vec<u8>> {
+    if text.contains(['+', '/', '=']) || text.len() % 4 == 1 {
+        return None;
+    }
+    let mut standard = text.replace('-', "+").replace('_', "/");
+    while !standard.len().is_multiple_of(4) {
+        standard.push('=');
+    }
+    decode(&standard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_url, encode};
+
+    #[test]
+    fn round_trips_rfc_4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (plain, encoded) in vectors {
+            assert_eq!(encode(plain.as_bytes()), encoded);
+            assert_eq!(decode(encoded).unwrap(), plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert_eq!(decode("Zm9"), None);
+        assert_eq!(decode("Zm=v"), None);
+        assert_eq!(decode("Zg==Zm9v"), None);
+        assert_eq!(decode("Zm9v!A=="), None);
+    }
+
+    #[test]
+    fn decodes_the_url_safe_alphabet() {
+        assert_eq!(decode_url("-_8").unwrap(), [0xFB, 0xFF]);
+        assert_eq!(decode_url("Zm9vYmE").unwrap(), b"fooba");
+        assert_eq!(decode_url("").unwrap(), b"");
+        assert!(decode_url("Zm9=").is_none());
+        assert!(decode_url("+/8").is_none());
+        assert!(decode_url("Zm9vY").is_none());
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{self, Read, Write},
+};
+
+// Streamed bodies are copied to the socket in chunks of this size, which bounds the
+// memory used per response no matter how large the body is.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Trailer fields computed once the body has been sent, see
+/// [`crate::response::Response::set_trailers`].
+pub(crate) type Trailers = Box<dyn FnOnce() -> Vec<(String, String)> + Send>;
+
+/// The payload of a [`crate::response::Response`].
+///
+/// Bodies held in memory are sent with a `Content-Length`. Streamed bodies are read while
+/// the response is written, so they never need to fit in memory; when their length is
+/// not known up front they go out with `Transfer-Encoding: chunked`.
+#[derive(Default)]
+pub enum Body {
+    #[default]
+    Empty,
+    Bytes(Vec<u8>),
+    /// Read from `reader` while the response is written. `length`, when known, must be
+    /// the exact number of bytes the reader produces.
+    Reader {
+        reader: Box<dyn Read + Send>,
+        length: Option<u64>,
+    },
+    /// Every item is sent as soon as the iterator yields it.
+    Chunks(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+}
+
+/// How the end of the body is communicated to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    Length,
+    Chunked,
+    /// The body ends when the connection is closed, used for HTTP/1.0 clients.
+    CloseDelimited,
+}
+
+impl Body {
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> Self {
+        Body::Reader {
+            reader: Box::new(reader),
+            length: None,
+        }
+    }
+
+    pub fn from_reader_with_length<R: Read + Send + 'static>(reader: R, length: u64) -> Self {
+        Body::Reader {
+            reader: Box::new(reader),
+            length: Some(length),
+        }
+    }
+
+    pub fn from_chunks<I>(chunks: I) -> Self
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        Body::Chunks(Box::new(chunks.into_iter()))
+    }
+
+    /// Streams the file from its current position to the end.
+    pub fn from_file(mut file: File) -> Self {
+        let remaining = file.metadata().ok().and_then(|metadata| {
+            let position = io::Seek::stream_position(&mut file).ok()?;
+            metadata.len().checked_sub(position)
+        });
+        Body::Reader {
+            reader: Box::new(file),
+            length: remaining,
+        }
+    }
+
+    /// The length of the body in bytes, `None` for streams of unknown length.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { length, .. } => *length,
+            Body::Chunks(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    pub(crate) fn is_streamed(&self) -> bool {
+        matches!(self, Body::Reader { .. } | Body::Chunks(_))
+    }
+
+    /// Writes the body out using `framing`. A reader that ends before its declared
+    /// length is an error, the response framing is broken at that point and the
+    /// connection has to be closed.
+    pub(crate) fn write_to<W: Write + ?Sized>(
+        self,
+        writer: &mut W,
+        framing: Framing,
+        trailers: Option<Trailers>,
+    ) -> io::Result<()> {
+        let mut sink = BodyWriter { writer, framing };
+        match self {
+            Body::Empty => {}
+            Body::Bytes(bytes) => sink.write_chunk(&bytes)?,
+            Body::Reader { reader, length } => {
+                let mut reader = reader.take(length.unwrap_or(u64::MAX));
+                let mut buffer = vec![0; CHUNK_SIZE.min(length.unwrap_or(u64::MAX) as usize)];
+                let mut written = 0;
+                loop {
+                    let bytes_read = reader.read(&mut buffer)?;
+                    if bytes_read == 0 {
+                        break;
+                    }
+                    sink.write_chunk(&buffer[..bytes_read])?;
+                    written += bytes_read as u64;
+                }
+                if length.is_some_and(|length| written < length) {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+            Body::Chunks(chunks) => {
+                // the first chunk may take a while, let the client see the head already
+                sink.writer.flush()?;
+                for chunk in chunks {
+                    sink.write_chunk(&chunk)?;
+                    // the client should see every chunk as soon as it is produced
+                    sink.writer.flush()?;
+                }
+            }
+        }
+        if framing == Framing::Chunked {
+            sink.writer.write_all(b"0\r\n")?;
+            for (name, value) in trailers.map(|trailers| trailers()).unwrap_or_default() {
+                sink.writer
+                    .write_all(format!("{name}: {value}\r\n").as_bytes())?;
+            }
+            sink.writer.write_all(b"\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Body::Empty => f.write_str("Body::Empty"),
+            Body::Bytes(bytes) => write!(f, "Body::Bytes({} bytes)", bytes.len()),
+            Body::Reader { length, .. } => write!(f, "Body::Reader {{ length: {length:?} }}"),
+            Body::Chunks(_) => f.write_str("Body::Chunks"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Self {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Self {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+impl From<File> for Body {
+    fn from(file: File) -> Self {
+        Body::from_file(file)
+    }
+}
+
+struct BodyWriter<'a, W: ?Sized> {
+    writer: &'a mut W,
+    framing: Framing,
+}
+
+impl<W: Write + ?Sized> BodyWriter<'_, W> {
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            // an empty chunk would end a chunked body early
+            return Ok(());
+        }
+        if self.framing == Framing::Chunked {
+            self.writer
+                .write_all(format!("{:x}\r\n", data.len()).as_bytes())?;
+            self.writer.write_all(data)?;
+            self.writer.write_all(b"\r\n")
+        } else {
+            self.writer.write_all(data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{Body, Framing};
+
+    fn written(body: Body, framing: Framing) -> Vec<u8> {
+        let mut buffer = vec![];
+        body.write_to(&mut buffer, framing, None).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn writes_chunked_bodies() {
+        let body = Body::from_chunks(vec![b"hello".to_vec(), vec![], b" world".to_vec()]);
+        assert_eq!(
+            written(body, Framing::Chunked),
+            b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"
+        );
+
+        let body = Body::from_reader(Cursor::new(b"abc".to_vec()));
+        assert_eq!(written(body, Framing::Chunked), b"3\r\nabc\r\n0\r\n\r\n");
+        let body = Body::from_reader(Cursor::new(b"abc".to_vec()));
+        assert_eq!(written(body, Framing::CloseDelimited), b"abc");
+    }
+
+    #[test]
+    fn writes_trailers_after_the_last_chunk() {
+        let mut buffer = vec![];
+        Body::from("data")
+            .write_to(
+                &mut buffer,
+                Framing::Chunked,
+                Some(Box::new(|| vec![("X-Checksum".into(), "42".into())])),
+            )
+            .unwrap();
+        assert_eq!(buffer, b"4\r\ndata\r\n0\r\nX-Checksum: 42\r\n\r\n");
+    }
+
+    #[test]
+    fn fails_when_a_reader_ends_early() {
+        let body = Body::from_reader_with_length(Cursor::new(b"abc".to_vec()), 10);
+        let mut buffer = vec![];
+        assert!(body.write_to(&mut buffer, Framing::Length, None).is_err());
+    }
+}
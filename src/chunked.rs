@@ -0,0 +1,220 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Read},
+};
+
+// Chunk size lines and trailer lines are tiny in practice, anything bigger than this
+// is treated as a malformed body rather than buffered.
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Size,
+    Data,
+    DataEnd,
+    Done,
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body from the underlying reader.
+///
+/// The decoder stops reading at the end of the chunked body, so any bytes that follow
+/// (a pipelined request for instance) are left untouched in `reader`. Chunk extensions
+/// are ignored and trailer fields are collected and returned by [`ChunkedDecoder::into_trailers`].
+pub(crate) struct ChunkedDecoder<R> {
+    reader: R,
+    state: State,
+    // bytes left to read in the current chunk
+    remaining: usize,
+    // total amount of body bytes decoded so far
+    decoded: usize,
+    max_size: usize,
+    trailers: HashMap<String, String>,
+}
+
+impl<R: BufRead> ChunkedDecoder<R> {
+    pub fn new(reader: R, max_size: usize) -> Self {
+        Self {
+            reader,
+            state: State::Size,
+            remaining: 0,
+            decoded: 0,
+            max_size,
+            trailers: HashMap::new(),
+        }
+    }
+
+    pub fn into_trailers(self) -> HashMap<String, String> {
+        self.trailers
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        let bytes_read = (&mut self.reader)
+            .take(MAX_LINE_LENGTH)
+            .read_line(&mut line)?;
+        if bytes_read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if !line.ends_with('\n') {
+            return Err(invalid_data("chunked body line too long"));
+        }
+        Ok(line)
+    }
+
+    fn read_chunk_size(&mut self) -> io::Result<usize> {
+        let line = self.read_line()?;
+        // chunk extensions follow the size and are separated by `;`
+        let size = line.split(';').next().unwrap_or_default().trim();
+        parse_length(size, 16).ok_or_else(|| invalid_data("invalid chunk size"))
+    }
+
+    fn read_trailers(&mut self) -> io::Result<()> {
+        loop {
+            let line = self.read_line()?;
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                return Ok(());
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                self.trailers
+                    .insert(name.trim().to_string(), value.trim().to_string());
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.state {
+                State::Size => {
+                    let size = self.read_chunk_size()?;
+                    if size == 0 {
+                        self.read_trailers()?;
+                        self.state = State::Done;
+                        continue;
+                    }
+                    if self.decoded.saturating_add(size) > self.max_size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::FileTooLarge,
+                            "chunked body exceeds the maximum body size",
+                        ));
+                    }
+                    self.remaining = size;
+                    self.state = State::Data;
+                }
+                State::Data => {
+                    if buf.is_empty() {
+                        return Ok(0);
+                    }
+                    let max = buf.len().min(self.remaining);
+                    let bytes_read = self.reader.read(&mut buf[..max])?;
+                    if bytes_read == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    self.remaining -= bytes_read;
+                    self.decoded += bytes_read;
+                    if self.remaining == 0 {
+                        self.state = State::DataEnd;
+                    }
+                    return Ok(bytes_read);
+                }
+                State::DataEnd => {
+                    let line = self.read_line()?;
+                    if line.trim_end_matches(['\r', '\n']).is_empty() {
+                        self.state = State::Size;
+                    } else {
+                        return Err(invalid_data("missing CRLF after chunk data"));
+                    }
+                }
+                State::Done => return Ok(0),
+            }
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parses a body or chunk length made of digits only. `from_str_radix` alone would also
+/// take a leading sign, which a proxy in front may read differently.
+pub(crate) fn parse_length(digits: &str, radix: u32) -> Option<usize> {
+    if digits.is_empty() || !digits.chars().all(|digit| digit.is_digit(radix)) {
+        return None;
+    }
+    usize::from_str_radix(digits, radix).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{self, BufRead, Cursor, Read},
+    };
+
+    use super::ChunkedDecoder;
+
+    fn decode(input: &str, max_size: usize) -> io::Result<(Vec<u8>, HashMap<String, String>)> {
+        let mut decoder = ChunkedDecoder::new(Cursor::new(input.as_bytes()), max_size);
+        let mut body = vec![];
+        decoder.read_to_end(&mut body)?;
+        Ok((body, decoder.into_trailers()))
+    }
+
+    #[test]
+    fn decodes_chunks() {
+        let (body, _) = decode(
+            "4\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\n\r\n",
+            1024,
+        )
+        .unwrap();
+        assert_eq!(body, b"Wikipedia in\r\n\r\nchunks.");
+    }
+
+    #[test]
+    fn ignores_extensions_and_collects_trailers() {
+        let (body, trailers) = decode(
+            "3;name=value\r\nabc\r\n0\r\nExpires: never\r\nX-Checksum: 42\r\n\r\n",
+            1024,
+        )
+        .unwrap();
+        assert_eq!(body, b"abc");
+        assert_eq!(trailers.get("Expires").unwrap(), "never");
+        assert_eq!(trailers.get("X-Checksum").unwrap(), "42");
+    }
+
+    #[test]
+    fn leaves_following_bytes_unread() {
+        let input = "2\r\nhi\r\n0\r\n\r\nGET / HTTP/1.1\r\n";
+        let mut cursor = Cursor::new(input.as_bytes());
+        let mut body = vec![];
+        ChunkedDecoder::new(&mut cursor, 1024)
+            .read_to_end(&mut body)
+            .unwrap();
+        assert_eq!(body, b"hi");
+        let mut rest = String::new();
+        cursor.read_line(&mut rest).unwrap();
+        assert_eq!(rest, "GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn enforces_max_size() {
+        let err = decode("4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n", 8)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        let err = decode("zz\r\nWiki\r\n0\r\n\r\n", 1024).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = decode("+4\r\nWiki\r\n0\r\n\r\n", 1024).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = decode("4\r\nWikipedia\r\n0\r\n\r\n", 1024).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = decode("4\r\nWi", 1024).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
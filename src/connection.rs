@@ -0,0 +1,374 @@
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    App, PanicHandler, ParseErrorHandler, Router,
+    extensions::Extensions,
+    http2::{self, H2cUpgrade},
+    request::{ParseError, Request, RequestMethod},
+    response::Response,
+    server::ConnectionGuard,
+};
+
+/// Per-connection limits and hooks, copied out of [`App`] for every accepted connection.
+#[derive(Clone)]
+pub(crate) struct ConnectionSettings {
+    pub max_body_size: usize,
+    pub keep_alive_timeout: Duration,
+    pub max_requests: usize,
+    /// Connections that can be served outside the worker pool at once, see [`serve`].
+    pub max_long_lived: usize,
+    pub on_parse_error: Option<Arc<ParseErrorHandler>>,
+    pub on_panic: Option<Arc<PanicHandler>>,
+    pub state: Extensions,
+}
+
+/// A connection requests are read from.
+pub(crate) trait Stream: Read + Write + Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Whether the connection is encrypted.
+    fn is_secure(&self) -> bool {
+        false
+    }
+
+    /// The protocol agreed on through ALPN, completing the TLS handshake if needed.
+    fn alpn_protocol(&mut self) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Splits the connection into halves that can be used from two threads at once.
+    /// Dropping the writing half closes the connection, which also ends a read blocked
+    /// on the other half.
+    fn split(self) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)>
+    where
+        Self: Sized,
+    {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Stream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn split(self) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        Ok((Box::new(self.try_clone()?), Box::new(TcpWriter(self))))
+    }
+}
+
+/// The writing half of a split [`TcpStream`].
+struct TcpWriter(TcpStream);
+
+impl Write for TcpWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for TcpWriter {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown(Shutdown::Both);
+    }
+}
+
+/// A connection taken over by another protocol after a `101 Switching Protocols`
+/// response.
+pub(crate) struct Upgraded {
+    pub stream: Box<dyn Stream>,
+    /// Bytes the client sent after the upgrade request that were already read off the
+    /// stream. They belong to the new protocol.
+    pub buffered: Vec<u8>,
+}
+
+pub(crate) type Upgrade = Box<dyn FnOnce(Upgraded) + Send>;
+
+/// A stream that gives back the bytes already read off it before reading any more.
+pub(crate) struct Replayed<S> {
+    replay: io::Cursor<Vec<u8>>,
+    stream: S,
+}
+
+impl<S> Replayed<S> {
+    fn new(replay: Vec<u8>, stream: S) -> Self {
+        Self {
+            replay: io::Cursor::new(replay),
+            stream,
+        }
+    }
+}
+
+impl<S: Read> Read for Replayed<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.replay.position() as usize) < self.replay.get_ref().len() {
+            return self.replay.read(buf);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for Replayed<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: Stream> Stream for Replayed<S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn is_secure(&self) -> bool {
+        self.stream.is_secure()
+    }
+
+    fn split(self) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        let (reader, writer) = self.stream.split()?;
+        let reader = Replayed {
+            replay: self.replay,
+            stream: reader,
+        };
+        Ok((Box::new(reader), writer))
+    }
+}
+
+/// Serves requests from `stream` until the client asks to close the connection, the
+/// request limit is reached, the connection sits idle past the keep-alive timeout or
+/// the server shuts down.
+///
+/// Requests are read and answered one after another, so pipelined requests get their
+/// responses in the order they were sent. Connections that negotiate HTTP/2 are handed
+/// over to [`http2::serve`]. The caller is expected to have set the read timeout on the
+/// underlying socket.
+///
+/// HTTP/2 connections, WebSockets and event streams can stay open for hours, they
+/// carry on on a thread of their own so the worker is free for other clients. Once
+/// [`ConnectionSettings::max_long_lived`] of them are open the worker keeps serving
+/// them itself.
+pub(crate) fn serve<S: Stream + 'static>(
+    mut stream: S,
+    router: &Arc<RwLock<Router>>,
+    settings: &ConnectionSettings,
+    guard: ConnectionGuard,
+) {
+    match stream.alpn_protocol() {
+        Ok(Some(protocol)) if protocol == http2::ALPN => {
+            return serve_http2(stream, Vec::new(), router, settings, guard, None);
+        }
+        Ok(_) => {}
+        Err(err) => {
+            log::debug!("Failed to set up the connection: {err}");
+            return;
+        }
+    }
+    // clients that know the server speaks HTTP/2 start with its preface right away
+    let mut sniffed = Vec::new();
+    if read_preface(&mut stream, &mut sniffed) {
+        return serve_http2(stream, sniffed, router, settings, guard, None);
+    }
+    let reader = BufReader::new(Replayed::new(sniffed, stream));
+    serve_http1(reader, 0, router, settings, guard);
+}
+
+fn serve_http1<S: Stream + 'static>(
+    mut reader: BufReader<Replayed<S>>,
+    mut served: usize,
+    router: &Arc<RwLock<Router>>,
+    settings: &ConnectionSettings,
+    mut guard: ConnectionGuard,
+) {
+    loop {
+        // a connection that waited in the queue while the server started shutting down
+        // still gets its first request answered
+        if served > 0 {
+            guard.set_idle(true);
+            if guard.is_shutting_down() {
+                return;
+            }
+        }
+        let request = match Request::from_reader(&mut reader, settings.max_body_size) {
+            Ok(request) => request,
+            Err(ParseError::ConnectionClosed) => return,
+            Err(err) => {
+                log::warn!("Rejecting malformed request: {err}");
+                // the rest of the stream can't be trusted, answer and close the connection
+                let response =
+                    parse_error_response(&err, settings).add_header("Connection", "close");
+                let _ = response.write_to(reader.get_mut(), false);
+                return;
+            }
+        };
+        guard.set_idle(false);
+        served += 1;
+        if !reader.get_ref().is_secure()
+            && let Some(h2_settings) = http2::upgrade_settings(&request)
+        {
+            let response = Response::new()
+                .set_status(101)
+                .add_header("Connection", "Upgrade")
+                .add_header("Upgrade", "h2c");
+            if response.write_to(reader.get_mut(), false).is_err() {
+                return;
+            }
+            let buffered = reader.buffer().to_vec();
+            let upgrade = H2cUpgrade {
+                request,
+                settings: h2_settings,
+            };
+            return serve_http2(
+                reader.into_inner(),
+                buffered,
+                router,
+                settings,
+                guard,
+                Some(upgrade),
+            );
+        }
+        let keep_alive = request.wants_keep_alive() && served < settings.max_requests;
+        let head_only = request.method == RequestMethod::Head;
+        // HTTP/1.0 clients don't understand chunked bodies
+        let chunked_allowed = !request.version.eq_ignore_ascii_case("HTTP/1.0");
+
+        let mut response = App::handle_request(router, request, settings);
+        if head_only {
+            // only the headers of the GET response are sent, there's nothing to upgrade
+            response.take_upgrade();
+        }
+        if let Some(upgrade) = response.take_upgrade() {
+            if response
+                .write_to(reader.get_mut(), chunked_allowed)
+                .is_err()
+            {
+                return;
+            }
+            // the new protocol has no requests to wait for, let shutdown close it like
+            // an idle connection
+            guard.set_idle(true);
+            if guard.is_shutting_down() {
+                return;
+            }
+            let buffered = reader.buffer().to_vec();
+            let stream: Box<dyn Stream> = Box::new(reader.into_inner());
+            return serve_long_lived(guard, settings, move |_guard| {
+                upgrade(Upgraded { stream, buffered })
+            });
+        }
+        // the server may have started shutting down while the handler was running, and
+        // bodies of unknown length sent to HTTP/1.0 clients end with the connection
+        let keep_alive = keep_alive
+            && !guard.is_shutting_down()
+            && (head_only || !response.is_close_delimited(chunked_allowed));
+        let response = if keep_alive {
+            response.add_header("Connection", "keep-alive").add_header(
+                "Keep-Alive",
+                &format!(
+                    "timeout={}, max={}",
+                    settings.keep_alive_timeout.as_secs(),
+                    settings.max_requests - served
+                ),
+            )
+        } else {
+            response.add_header("Connection", "close")
+        };
+        if response.is_event_stream() && !head_only {
+            // an event stream has no end the server could wait for, let shutdown close
+            // it like an idle connection
+            guard.set_idle(true);
+            if guard.is_shutting_down() {
+                return;
+            }
+            if guard.detach(settings.max_long_lived) {
+                let (router, settings) = (router.clone(), settings.clone());
+                return spawn_long_lived(move || {
+                    if response.write_to(reader.get_mut(), chunked_allowed).is_ok() && keep_alive {
+                        serve_http1(reader, served, &router, &settings, guard);
+                    }
+                });
+            }
+        }
+
+        let written = if head_only {
+            response.write_head_to(reader.get_mut(), chunked_allowed)
+        } else {
+            response.write_to(reader.get_mut(), chunked_allowed)
+        };
+        if written.is_err() || !keep_alive {
+            return;
+        }
+    }
+}
+
+fn serve_http2<S: Stream + 'static>(
+    stream: S,
+    buffered: Vec<u8>,
+    router: &Arc<RwLock<Router>>,
+    settings: &ConnectionSettings,
+    guard: ConnectionGuard,
+    upgrade: Option<H2cUpgrade>,
+) {
+    let (router, owned_settings) = (router.clone(), settings.clone());
+    serve_long_lived(guard, settings, move |guard| {
+        http2::serve(stream, buffered, &router, &owned_settings, &guard, upgrade)
+    });
+}
+
+/// Runs `serve` on a thread of its own if the connection can be detached from its
+/// worker, or right away otherwise.
+fn serve_long_lived<F>(mut guard: ConnectionGuard, settings: &ConnectionSettings, serve: F)
+where
+    F: FnOnce(ConnectionGuard) + Send + 'static,
+{
+    if guard.detach(settings.max_long_lived) {
+        spawn_long_lived(move || serve(guard));
+    } else {
+        serve(guard);
+    }
+}
+
+fn spawn_long_lived<F: FnOnce() + Send + 'static>(serve: F) {
+    if let Err(err) = thread::Builder::new()
+        .name("http-long-lived".into())
+        .spawn(serve)
+    {
+        log::error!("Failed to start a thread for a long-lived connection: {err}");
+    }
+}
+
+/// Reads from `stream` into `sniffed` until it holds the whole HTTP/2 preface or a byte
+/// that isn't part of it. The first bytes of `POST` or `PUT` match the preface too, so
+/// nothing short of the full preface counts.
+fn read_preface<S: Read>(stream: &mut S, sniffed: &mut Vec<u8>) -> bool {
+    let mut buffer = [0; 8 * 1024];
+    while sniffed.len() < http2::PREFACE.len() && http2::PREFACE.starts_with(sniffed) {
+        match stream.read(&mut buffer) {
+            Ok(0) => return false,
+            Ok(read) => sniffed.extend_from_slice(&buffer[..read]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            // the request parser runs into the same error and reports it
+            Err(_) => return false,
+        }
+    }
+    sniffed.starts_with(http2::PREFACE)
+}
+
+pub(crate) fn parse_error_response(err: &ParseError, settings: &ConnectionSettings) -> Response {
+    let response = Response::new().set_status(err.status());
+    match &settings.on_parse_error {
+        Some(on_parse_error) => on_parse_error(err, response),
+        None => response.set_body(&err.to_string()),
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt::Debug,
+    sync::Arc,
+};
+
+/// Values keyed by their type. Holds the state given to [`crate::App::with_state`] and
+/// whatever middleware attaches to a request for the handlers after it.
+///
+/// Values are reference counted, so cloning the map for every request is cheap and
+/// doesn't require the values to be `Clone`.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores `value`, replacing the value of the same type if there was one.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.map.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    /// Drops the value of type `T`, returning whether there was one.
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> bool {
+        self.map.remove(&TypeId::of::<T>()).is_some()
+    }
+}
+
+impl Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Extensions;
+
+    #[derive(Debug, PartialEq)]
+    struct Config {
+        name: &'static str,
+    }
+
+    #[test]
+    fn stores_one_value_per_type() {
+        let mut extensions = Extensions::new();
+        assert_eq!(extensions.get::<Config>(), None);
+        extensions.insert(Config { name: "first" });
+        extensions.insert(7u32);
+        extensions.insert(Config { name: "second" });
+        assert_eq!(extensions.get::<Config>(), Some(&Config { name: "second" }));
+        assert_eq!(extensions.get::<u32>(), Some(&7));
+        assert!(!extensions.contains::<u64>());
+
+        let cloned = extensions.clone();
+        assert!(extensions.remove::<u32>());
+        assert!(!extensions.remove::<u32>());
+        assert_eq!(cloned.get::<u32>(), Some(&7));
+    }
+}
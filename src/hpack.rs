@@ -0,0 +1,421 @@
+use std::collections::VecDeque;
+
+use crate::huffman;
+
+// RFC 7541 appendix A. Index 1 is the first entry, indices past the static table
+// address the dynamic table.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Size of the dynamic table until the peer says otherwise, RFC 7540 section 6.5.2.
+pub(crate) const DEFAULT_TABLE_SIZE: usize = 4096;
+
+// Every dynamic table entry costs this much on top of its name and value.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// A header block that can't be decoded. The decoder state can't be trusted afterwards,
+/// so this is a connection error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CompressionError(pub &'static str);
+
+/// Decodes header blocks sent by the client. The dynamic table lives as long as the
+/// connection, so every block has to go through the same decoder in the order received.
+pub(crate) struct Decoder {
+    table: VecDeque<(String, String)>,
+    table_size: usize,
+    max_table_size: usize,
+    // the size we advertised in SETTINGS_HEADER_TABLE_SIZE, updates can't exceed it
+    table_size_limit: usize,
+}
+
+impl Decoder {
+    pub fn new(table_size_limit: usize) -> Self {
+        Self {
+            table: VecDeque::new(),
+            table_size: 0,
+            max_table_size: table_size_limit,
+            table_size_limit,
+        }
+    }
+
+    /// Decodes `block`, failing once the header list grows past `max_list_size` as
+    /// counted for SETTINGS_MAX_HEADER_LIST_SIZE.
+    pub fn decode(
+        &mut self,
+        block: &[u8],
+        max_list_size: usize,
+    ) -> Result<Vec<(String, String)>, CompressionError> {
+        let mut headers = vec![];
+        let mut list_size = 0;
+        let mut input = block;
+        while let Some(&first) = input.first() {
+            let header = if first & 0x80 != 0 {
+                let index = decode_integer(&mut input, 7)?;
+                self.entry(index)?
+            } else if first & 0xC0 == 0x40 {
+                let header = self.decode_literal(&mut input, 6)?;
+                self.insert(header.clone());
+                header
+            } else if first & 0xE0 == 0x20 {
+                // size updates are only allowed at the start of a block
+                if !headers.is_empty() {
+                    return Err(CompressionError("misplaced table size update"));
+                }
+                let size = decode_integer(&mut input, 5)?;
+                if size > self.table_size_limit {
+                    return Err(CompressionError("table size update above the limit"));
+                }
+                self.max_table_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // literals without indexing and never indexed differ only for proxies
+                self.decode_literal(&mut input, 4)?
+            };
+            // a one byte index can stand for a whole table entry, so the list is
+            // checked as it grows rather than once the block is decoded
+            list_size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+            if list_size > max_list_size {
+                return Err(CompressionError("header list too large"));
+            }
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    fn decode_literal(
+        &self,
+        input: &mut &[u8],
+        prefix: u8,
+    ) -> Result<(String, String), CompressionError> {
+        let index = decode_integer(input, prefix)?;
+        let name = if index == 0 {
+            decode_string(input)?
+        } else {
+            self.entry(index)?.0
+        };
+        let value = decode_string(input)?;
+        Ok((name, value))
+    }
+
+    fn entry(&self, index: usize) -> Result<(String, String), CompressionError> {
+        match index {
+            0 => Err(CompressionError("index 0")),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            }
+            _ => self
+                .table
+                .get(index - 62)
+                .cloned()
+                .ok_or(CompressionError("index out of range")),
+        }
+    }
+
+    fn insert(&mut self, header: (String, String)) {
+        let size = header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // an entry larger than the whole table empties it and isn't added
+        if size <= self.max_table_size {
+            self.table_size += size;
+            self.table.push_front(header);
+        }
+    }
+
+    // Drops the oldest entries until `incoming` more bytes fit.
+    fn evict(&mut self, incoming: usize) {
+        while self.table_size + incoming > self.max_table_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.table_size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+/// Encodes a header block. Nothing is added to the dynamic table, so the client's
+/// table size setting never matters and blocks can be encoded in any order.
+pub(crate) fn encode<'a>(headers: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let mut block = vec![];
+    for (name, value) in headers {
+        let exact = STATIC_TABLE
+            .iter()
+            .position(|&(static_name, static_value)| static_name == name && static_value == value);
+        if let Some(index) = exact {
+            encode_integer(&mut block, 0x80, 7, index + 1);
+            continue;
+        }
+        // literal header field without indexing
+        match STATIC_TABLE
+            .iter()
+            .position(|&(static_name, _)| static_name == name)
+        {
+            Some(index) => encode_integer(&mut block, 0x00, 4, index + 1),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name.as_bytes());
+            }
+        }
+        encode_string(&mut block, value.as_bytes());
+    }
+    block
+}
+
+fn decode_integer(input: &mut &[u8], prefix: u8) -> Result<usize, CompressionError> {
+    let truncated = CompressionError("truncated integer");
+    let (&first, rest) = input.split_first().ok_or(truncated)?;
+    *input = rest;
+    let max_prefix = (1 << prefix) - 1;
+    let mut value = usize::from(first) & max_prefix;
+    if value < max_prefix {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = input.split_first().ok_or(truncated)?;
+        *input = rest;
+        if shift > 28 {
+            return Err(CompressionError("integer overflow"));
+        }
+        value += usize::from(byte & 0x7F) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn decode_string(input: &mut &[u8]) -> Result<String, CompressionError> {
+    let huffman_encoded = input.first().is_some_and(|byte| byte & 0x80 != 0);
+    let length = decode_integer(input, 7)?;
+    if length > input.len() {
+        return Err(CompressionError("truncated string"));
+    }
+    let (raw, rest) = input.split_at(length);
+    *input = rest;
+    let bytes = if huffman_encoded {
+        huffman::decode(raw).ok_or(CompressionError("invalid Huffman code"))?
+    } else {
+        raw.to_vec()
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, value: usize) {
+    let max_prefix = (1 << prefix) - 1;
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max_prefix as u8);
+    let mut value = value - max_prefix;
+    while value >= 0x80 {
+        block.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn encode_string(block: &mut Vec<u8>, data: &[u8]) {
+    if huffman::encoded_len(data) < data.len() {
+        let encoded = huffman::encode(data);
+        encode_integer(block, 0x80, 7, encoded.len());
+        block.extend_from_slice(&encoded);
+    } else {
+        encode_integer(block, 0x00, 7, data.len());
+        block.extend_from_slice(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompressionError, Decoder, decode_integer, encode, encode_integer};
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_integers() {
+        // RFC 7541 appendix C.1
+        let mut block = vec![];
+        encode_integer(&mut block, 0, 5, 1337);
+        assert_eq!(block, [0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_integer(&mut block.as_slice(), 5), Ok(1337));
+        assert_eq!(decode_integer(&mut [0x0a].as_slice(), 5), Ok(10));
+        assert!(decode_integer(&mut [0x1f, 0xff].as_slice(), 5).is_err());
+    }
+
+    #[test]
+    fn decodes_requests_with_huffman_and_the_dynamic_table() {
+        // RFC 7541 appendix C.4, three requests sharing one dynamic table
+        let mut decoder = Decoder::new(4096);
+        let first = [
+            0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab,
+            0x90, 0xf4, 0xff,
+        ];
+        assert_eq!(
+            decoder.decode(&first, usize::MAX).unwrap(),
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        let second = [
+            0x82, 0x86, 0x84, 0xbe, 0x58, 0x86, 0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf,
+        ];
+        assert_eq!(
+            decoder.decode(&second, usize::MAX).unwrap(),
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+        let third = [
+            0x82, 0x87, 0x85, 0xbf, 0x40, 0x88, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xa9, 0x7d, 0x7f,
+            0x89, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xb8, 0xe8, 0xb4, 0xbf,
+        ];
+        assert_eq!(
+            decoder.decode(&third, usize::MAX).unwrap(),
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.table_size, 164);
+    }
+
+    #[test]
+    fn evicts_entries_past_the_table_size() {
+        let mut decoder = Decoder::new(4096);
+        // shrink the table so a single entry fits, then add two
+        let mut block = vec![0x3f, 0x1b];
+        block.extend([0x40, 0x01, b'a', 0x01, b'1']);
+        block.extend([0x40, 0x01, b'b', 0x01, b'2']);
+        decoder.decode(&block, usize::MAX).unwrap();
+        assert_eq!(
+            decoder.decode(&[0xbe], usize::MAX).unwrap(),
+            headers(&[("b", "2")])
+        );
+        assert!(decoder.decode(&[0xbf], usize::MAX).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_blocks() {
+        let mut decoder = Decoder::new(4096);
+        assert_eq!(
+            decoder.decode(&[0x80], usize::MAX),
+            Err(CompressionError("index 0"))
+        );
+        assert!(decoder.decode(&[0xc0], usize::MAX).is_err());
+        // size update above the advertised limit
+        assert!(decoder.decode(&[0x3f, 0xe2, 0x1f], usize::MAX).is_err());
+        // size update after a header
+        assert!(decoder.decode(&[0x82, 0x20], usize::MAX).is_err());
+        assert!(decoder.decode(&[0x40, 0x05, b'a'], usize::MAX).is_err());
+    }
+
+    #[test]
+    fn stops_at_the_header_list_limit() {
+        let mut decoder = Decoder::new(4096);
+        let mut block = vec![0x40, 0x01, b'a', 0x7f, 0x00];
+        block.extend([b'x'; 127]);
+        // every further byte refers to the entry just added
+        block.extend([0xbe; 1000]);
+        assert_eq!(
+            decoder.decode(&block, 16 * 1024),
+            Err(CompressionError("header list too large"))
+        );
+        assert_eq!(decoder.decode(&[0xbe; 100], 16 * 1024).unwrap().len(), 100);
+    }
+
+    #[test]
+    fn encodes_blocks_the_decoder_understands() {
+        let response = [
+            (":status", "200"),
+            (":status", "302"),
+            ("content-type", "text/html; charset=utf-8"),
+            ("x-request-id", "f0e1"),
+        ];
+        let block = encode(response);
+        // `:status: 200` comes straight from the static table
+        assert_eq!(block[0], 0x88);
+        assert_eq!(
+            Decoder::new(4096).decode(&block, usize::MAX).unwrap(),
+            headers(&response)
+        );
+    }
+}
@@ -0,0 +1,1184 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read, Write},
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SendError, SyncSender, TryRecvError},
+    },
+    thread::{self, Scope},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    App, Router, base64,
+    body::Framing,
+    connection::{ConnectionSettings, Stream, parse_error_response},
+    hpack,
+    request::{self, ParseError, Request, RequestMethod},
+    response::Response,
+    server::ConnectionGuard,
+};
+
+/// What a client sends first on a connection that speaks HTTP/2 from the start.
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The ALPN protocol id for HTTP/2 over TLS.
+pub(crate) const ALPN: &[u8] = b"h2";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xB;
+
+const FRAME_HEADER_LEN: usize = 9;
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+const MAX_ALLOWED_FRAME_SIZE: usize = (1 << 24) - 1;
+const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+// Every stream runs its handler on a thread of its own, outside the worker pool, so
+// this bounds the open streams as well as the handlers still running for streams the
+// client reset.
+const MAX_CONCURRENT_STREAMS: usize = 8;
+const MAX_HEADER_LIST_SIZE: usize = 64 * 1024;
+// Header blocks are buffered until complete, this bounds the CONTINUATION frames.
+const MAX_HEADER_BLOCK_SIZE: usize = 4 * MAX_HEADER_LIST_SIZE;
+// Body chunks queued by a handler before it blocks on the connection.
+const OUTPUT_QUEUE_DEPTH: usize = 8;
+// Input and wake-ups queued for the connection before the reader and handlers block.
+const EVENT_QUEUE_DEPTH: usize = 32;
+
+// Headers that only mean something to an HTTP/1.1 connection.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// A request that arrived over HTTP/1.1 with `Upgrade: h2c`. It becomes stream 1 and
+/// is answered over HTTP/2.
+pub(crate) struct H2cUpgrade {
+    pub request: Request,
+    pub settings: Vec<u8>,
+}
+
+/// The settings from the `HTTP2-Settings` header when `request` asks to switch to
+/// HTTP/2 with `Upgrade: h2c`, `None` when it should be answered over HTTP/1.1 as usual.
+pub(crate) fn upgrade_settings(request: &Request) -> Option<Vec<u8>> {
+    let has_token = |name: &str, token: &str| {
+        request.header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|option| option.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    if !has_token("Upgrade", "h2c")
+        || !has_token("Connection", "upgrade")
+        || !has_token("Connection", "http2-settings")
+    {
+        return None;
+    }
+    let settings = base64::decode_url(request.header("HTTP2-Settings")?.trim())?;
+    settings.len().is_multiple_of(6).then_some(settings)
+}
+
+/// A problem that ends the whole connection.
+struct ConnectionError(u32, &'static str);
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+/// What the connection thread wakes up for.
+enum Event {
+    /// Bytes the client sent.
+    Input(Vec<u8>),
+    /// The client closed the connection or reading from it failed.
+    Closed,
+    /// A handler queued output for its stream, or finished.
+    Output,
+}
+
+/// What a handler thread hands to the connection for its stream.
+enum Output {
+    Head {
+        status: u16,
+        headers: Vec<(String, String)>,
+        end_stream: bool,
+    },
+    Data(Vec<u8>),
+    Trailers(Vec<(String, String)>),
+    End,
+    Reset(u32),
+}
+
+struct HeaderBlock {
+    stream_id: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+/// The request side of a stream while it is still being received.
+struct PendingRequest {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    trailers: Vec<(String, String)>,
+    too_large: bool,
+}
+
+struct StreamState {
+    request: Option<PendingRequest>,
+    recv_closed: bool,
+    send_window: i64,
+    response: Option<Receiver<Output>>,
+    // a chunk of the body waiting for the flow control window, with how much was sent
+    pending: Option<(Vec<u8>, usize)>,
+}
+
+impl StreamState {
+    fn new(send_window: i64) -> Self {
+        Self {
+            request: None,
+            recv_closed: false,
+            send_window,
+            response: None,
+            pending: None,
+        }
+    }
+}
+
+/// Serves an HTTP/2 connection. `buffered` holds bytes already read off `stream`, and
+/// `upgrade` the request that switched the connection over from HTTP/1.1.
+///
+/// The calling thread handles the frames and does all the writing on the socket. A
+/// second thread reads from the socket and every request runs on a thread of its own,
+/// so a slow handler doesn't hold up the other streams. Both wake the calling thread
+/// up through a channel, it sleeps while there's nothing to do.
+pub(crate) fn serve<S: Stream>(
+    stream: S,
+    buffered: Vec<u8>,
+    router: &RwLock<Router>,
+    settings: &ConnectionSettings,
+    guard: &ConnectionGuard,
+    upgrade: Option<H2cUpgrade>,
+) {
+    // the reader waits for the client as long as it takes, the connection keeps time
+    let halves = stream.set_read_timeout(None).and_then(|()| stream.split());
+    let (reader, writer) = match halves {
+        Ok(halves) => halves,
+        Err(err) => {
+            log::debug!("Failed to set up the HTTP/2 connection: {err}");
+            return;
+        }
+    };
+    let (wake, events) = mpsc::sync_channel(EVENT_QUEUE_DEPTH);
+    thread::scope(|scope| {
+        let input = wake.clone();
+        scope.spawn(move || read_input(reader, &input));
+        let mut connection = Connection {
+            writer,
+            events,
+            wake,
+            input: buffered,
+            output: Vec::new(),
+            scope,
+            router,
+            settings,
+            guard,
+            decoder: hpack::Decoder::new(hpack::DEFAULT_TABLE_SIZE),
+            streams: BTreeMap::new(),
+            last_stream_id: 0,
+            header_block: None,
+            send_window: DEFAULT_WINDOW_SIZE,
+            peer_initial_window: DEFAULT_WINDOW_SIZE,
+            peer_max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            going_away: false,
+            running: Arc::new(AtomicUsize::new(0)),
+            last_input: Instant::now(),
+        };
+        match connection.run(upgrade) {
+            Ok(()) => {}
+            Err(ConnectionError(code, reason)) => {
+                log::debug!("Closing HTTP/2 connection: {reason}");
+                if code != NO_ERROR {
+                    connection.goaway(code);
+                    let _ = connection.flush();
+                }
+            }
+        }
+        // dropping the streams stops handlers still sending their responses, and
+        // dropping the writer closes the socket under the reader
+    });
+}
+
+/// Runs on a thread of its own, handing whatever the client sends to the connection.
+fn read_input(mut reader: Box<dyn Read + Send>, events: &SyncSender<Event>) {
+    let mut buf = [0; 16 * 1024];
+    loop {
+        let event = match reader.read(&mut buf) {
+            Ok(0) => Event::Closed,
+            Ok(read) => Event::Input(buf[..read].to_vec()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => Event::Closed,
+        };
+        let closed = matches!(event, Event::Closed);
+        if events.send(event).is_err() || closed {
+            return;
+        }
+    }
+}
+
+struct Connection<'scope, 'env> {
+    writer: Box<dyn Write + Send>,
+    events: Receiver<Event>,
+    // handed to handler threads so they can wake the connection up
+    wake: SyncSender<Event>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    scope: &'scope Scope<'scope, 'env>,
+    router: &'env RwLock<Router>,
+    settings: &'env ConnectionSettings,
+    guard: &'env ConnectionGuard,
+    decoder: hpack::Decoder,
+    streams: BTreeMap<u32, StreamState>,
+    last_stream_id: u32,
+    header_block: Option<HeaderBlock>,
+    send_window: i64,
+    peer_initial_window: i64,
+    peer_max_frame_size: usize,
+    going_away: bool,
+    // handler threads that haven't returned yet, streams reset meanwhile included
+    running: Arc<AtomicUsize>,
+    last_input: Instant,
+}
+
+impl<'scope, 'env> Connection<'scope, 'env> {
+    fn run(&mut self, upgrade: Option<H2cUpgrade>) -> Result<(), ConnectionError> {
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEADER_LIST_SIZE),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        self.frame(SETTINGS, 0, 0, &settings);
+        if let Some(upgrade) = upgrade {
+            self.apply_settings(&upgrade.settings)?;
+            // the upgrade request is stream 1, already fully received
+            self.last_stream_id = 1;
+            let mut state = StreamState::new(self.peer_initial_window);
+            state.recv_closed = true;
+            self.streams.insert(1, state);
+            self.respond(1, upgrade.request);
+        }
+        self.flush()?;
+
+        while self.input.len() < PREFACE.len() {
+            if !self.wait(Some(self.settings.keep_alive_timeout))? {
+                return Err(ConnectionError(NO_ERROR, "no preface from the client"));
+            }
+        }
+        if !self.input.starts_with(PREFACE) {
+            return Err(ConnectionError(
+                PROTOCOL_ERROR,
+                "invalid connection preface",
+            ));
+        }
+        self.input.drain(..PREFACE.len());
+        let mut preface_settings = true;
+
+        let mut idle_since = Instant::now();
+        loop {
+            while let Some(frame) = self.next_frame()? {
+                if preface_settings && (frame.kind != SETTINGS || frame.flags & ACK != 0) {
+                    return Err(ConnectionError(PROTOCOL_ERROR, "expected SETTINGS first"));
+                }
+                preface_settings = false;
+                self.handle_frame(frame)?;
+            }
+            self.send_responses();
+            if self.guard.is_shutting_down() && !self.going_away {
+                self.goaway(NO_ERROR);
+            }
+            self.flush()?;
+
+            let idle = self.streams.is_empty();
+            self.guard.set_idle(idle);
+            if idle && self.going_away {
+                return Ok(());
+            }
+            if !idle {
+                idle_since = Instant::now();
+                // handlers wake the connection up once they have something to send, but
+                // a client that stops sending a request gets the keep-alive timeout
+                if self.streams.values().all(|state| state.recv_closed) {
+                    self.wait(None)?;
+                    continue;
+                }
+                let remaining = self
+                    .settings
+                    .keep_alive_timeout
+                    .saturating_sub(self.last_input.elapsed());
+                if remaining.is_zero() {
+                    self.goaway(NO_ERROR);
+                    self.flush()?;
+                    return Ok(());
+                }
+                self.wait(Some(remaining))?;
+                continue;
+            }
+            let remaining = self
+                .settings
+                .keep_alive_timeout
+                .saturating_sub(idle_since.elapsed());
+            if remaining.is_zero() || self.guard.is_shutting_down() {
+                self.goaway(NO_ERROR);
+                self.flush()?;
+                return Ok(());
+            }
+            self.wait(Some(remaining))?;
+        }
+    }
+
+    /// Sleeps until the client sends something or a handler has output, returning
+    /// `false` if `timeout` passed first.
+    fn wait(&mut self, timeout: Option<Duration>) -> Result<bool, ConnectionError> {
+        // the connection holds a sender itself, so the channel can't disconnect
+        let event = match timeout {
+            Some(timeout) => match self.events.recv_timeout(timeout) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => return Ok(false),
+                Err(RecvTimeoutError::Disconnected) => Event::Closed,
+            },
+            None => self.events.recv().unwrap_or(Event::Closed),
+        };
+        match event {
+            Event::Input(bytes) => {
+                self.input.extend_from_slice(&bytes);
+                self.last_input = Instant::now();
+            }
+            Event::Output => {}
+            Event::Closed => {
+                return Err(ConnectionError(
+                    NO_ERROR,
+                    "the client closed the connection",
+                ));
+            }
+        }
+        Ok(true)
+    }
+
+    fn flush(&mut self) -> Result<(), ConnectionError> {
+        if self.output.is_empty() {
+            return Ok(());
+        }
+        let output = std::mem::take(&mut self.output);
+        self.writer
+            .write_all(&output)
+            .and_then(|_| self.writer.flush())
+            .map_err(|_| ConnectionError(NO_ERROR, "failed to write to the client"))
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Frame>, ConnectionError> {
+        if self.input.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes([0, self.input[0], self.input[1], self.input[2]]) as usize;
+        if length > DEFAULT_MAX_FRAME_SIZE {
+            return Err(ConnectionError(FRAME_SIZE_ERROR, "frame too large"));
+        }
+        if self.input.len() < FRAME_HEADER_LEN + length {
+            return Ok(None);
+        }
+        let frame = Frame {
+            kind: self.input[3],
+            flags: self.input[4],
+            stream_id: u32::from_be_bytes(self.input[5..9].try_into().unwrap()) & 0x7FFF_FFFF,
+            payload: self.input[FRAME_HEADER_LEN..FRAME_HEADER_LEN + length].to_vec(),
+        };
+        self.input.drain(..FRAME_HEADER_LEN + length);
+        Ok(Some(frame))
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if let Some(header_block) = &mut self.header_block {
+            if frame.kind != CONTINUATION || frame.stream_id != header_block.stream_id {
+                return Err(ConnectionError(PROTOCOL_ERROR, "expected CONTINUATION"));
+            }
+            header_block.block.extend_from_slice(&frame.payload);
+            if header_block.block.len() > MAX_HEADER_BLOCK_SIZE {
+                return Err(ConnectionError(ENHANCE_YOUR_CALM, "header block too large"));
+            }
+            if frame.flags & END_HEADERS != 0 {
+                let header_block = self.header_block.take().unwrap();
+                self.handle_header_block(header_block)?;
+            }
+            return Ok(());
+        }
+        match frame.kind {
+            DATA => self.handle_data(frame),
+            HEADERS => self.handle_headers(frame),
+            PRIORITY => {
+                if frame.stream_id == 0 || frame.payload.len() != 5 {
+                    return Err(ConnectionError(PROTOCOL_ERROR, "invalid PRIORITY"));
+                }
+                Ok(())
+            }
+            RST_STREAM => {
+                if frame.stream_id == 0 || frame.payload.len() != 4 {
+                    return Err(ConnectionError(PROTOCOL_ERROR, "invalid RST_STREAM"));
+                }
+                if frame.stream_id > self.last_stream_id {
+                    return Err(ConnectionError(PROTOCOL_ERROR, "RST_STREAM on idle stream"));
+                }
+                // dropping the receiver stops the handler at its next write
+                self.streams.remove(&frame.stream_id);
+                Ok(())
+            }
+            SETTINGS => {
+                if frame.stream_id != 0 {
+                    return Err(ConnectionError(PROTOCOL_ERROR, "SETTINGS on a stream"));
+                }
+                if frame.flags & ACK != 0 {
+                    if !frame.payload.is_empty() {
+                        return Err(ConnectionError(
+                            FRAME_SIZE_ERROR,
+                            "SETTINGS ACK with payload",
+                        ));
+                    }
+                    return Ok(());
+                }
+                if !frame.payload.len().is_multiple_of(6) {
+                    return Err(ConnectionError(FRAME_SIZE_ERROR, "invalid SETTINGS"));
+                }
+                self.apply_settings(&frame.payload)?;
+                self.frame(SETTINGS, ACK, 0, &[]);
+                Ok(())
+            }
+            PUSH_PROMISE => Err(ConnectionError(PROTOCOL_ERROR, "clients can't push")),
+            PING => {
+                if frame.stream_id != 0 {
+                    return Err(ConnectionError(PROTOCOL_ERROR, "PING on a stream"));
+                }
+                if frame.payload.len() != 8 {
+                    return Err(ConnectionError(FRAME_SIZE_ERROR, "invalid PING"));
+                }
+                if frame.flags & ACK == 0 {
+                    self.frame(PING, ACK, 0, &frame.payload);
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                if frame.stream_id != 0 {
+                    return Err(ConnectionError(PROTOCOL_ERROR, "GOAWAY on a stream"));
+                }
+                // finish what was started, the client won't open new streams
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.handle_window_update(frame),
+            CONTINUATION => Err(ConnectionError(PROTOCOL_ERROR, "unexpected CONTINUATION")),
+            // unknown frame types must be ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), ConnectionError> {
+        for setting in payload.chunks_exact(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(ConnectionError(PROTOCOL_ERROR, "invalid ENABLE_PUSH"));
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = i64::from(value);
+                    if value > MAX_WINDOW_SIZE {
+                        return Err(ConnectionError(FLOW_CONTROL_ERROR, "window too large"));
+                    }
+                    let delta = value - self.peer_initial_window;
+                    self.peer_initial_window = value;
+                    for state in self.streams.values_mut() {
+                        state.send_window += delta;
+                        if state.send_window > MAX_WINDOW_SIZE {
+                            return Err(ConnectionError(FLOW_CONTROL_ERROR, "window too large"));
+                        }
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    let value = value as usize;
+                    if !(DEFAULT_MAX_FRAME_SIZE..=MAX_ALLOWED_FRAME_SIZE).contains(&value) {
+                        return Err(ConnectionError(PROTOCOL_ERROR, "invalid MAX_FRAME_SIZE"));
+                    }
+                    self.peer_max_frame_size = value;
+                }
+                // responses are encoded without the dynamic table so HEADER_TABLE_SIZE
+                // doesn't matter, and everything else is advisory
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_headers(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let stream_id = frame.stream_id;
+        if stream_id == 0 {
+            return Err(ConnectionError(PROTOCOL_ERROR, "HEADERS on stream 0"));
+        }
+        let mut fragment = strip_padding(frame.flags, &frame.payload)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            if fragment.len() < 5 {
+                return Err(ConnectionError(PROTOCOL_ERROR, "invalid HEADERS"));
+            }
+            fragment = &fragment[5..];
+        }
+        let header_block = HeaderBlock {
+            stream_id,
+            end_stream: frame.flags & END_STREAM != 0,
+            block: fragment.to_vec(),
+        };
+        if frame.flags & END_HEADERS == 0 {
+            self.header_block = Some(header_block);
+            return Ok(());
+        }
+        self.handle_header_block(header_block)
+    }
+
+    fn handle_header_block(&mut self, header_block: HeaderBlock) -> Result<(), ConnectionError> {
+        let HeaderBlock {
+            stream_id,
+            end_stream,
+            block,
+        } = header_block;
+        // decoded even for streams that get refused, it updates the shared table
+        let headers = self.decoder.decode(&block, MAX_HEADER_LIST_SIZE).map_err(
+            |hpack::CompressionError(reason)| ConnectionError(COMPRESSION_ERROR, reason),
+        )?;
+
+        if let Some(state) = self.streams.get_mut(&stream_id) {
+            // trailers, they have to end the request
+            if state.recv_closed {
+                self.reset(stream_id, STREAM_CLOSED);
+                return Ok(());
+            }
+            if !end_stream {
+                return Err(ConnectionError(
+                    PROTOCOL_ERROR,
+                    "trailers without END_STREAM",
+                ));
+            }
+            state.recv_closed = true;
+            if let Some(request) = &mut state.request {
+                request.trailers = headers;
+            }
+            self.dispatch(stream_id);
+            return Ok(());
+        }
+        if stream_id <= self.last_stream_id {
+            if stream_id % 2 == 1 {
+                // a stream that was already closed
+                self.reset(stream_id, STREAM_CLOSED);
+                return Ok(());
+            }
+            return Err(ConnectionError(PROTOCOL_ERROR, "invalid stream id"));
+        }
+        if stream_id % 2 == 0 {
+            return Err(ConnectionError(
+                PROTOCOL_ERROR,
+                "even stream id from the client",
+            ));
+        }
+        self.last_stream_id = stream_id;
+        if self.going_away {
+            return Ok(());
+        }
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS
+            || self.running.load(Ordering::SeqCst) >= MAX_CONCURRENT_STREAMS
+        {
+            self.reset(stream_id, REFUSED_STREAM);
+            return Ok(());
+        }
+        let mut state = StreamState::new(self.peer_initial_window);
+        state.recv_closed = end_stream;
+        state.request = Some(PendingRequest {
+            headers,
+            body: Vec::new(),
+            trailers: Vec::new(),
+            too_large: false,
+        });
+        self.streams.insert(stream_id, state);
+        if end_stream {
+            self.dispatch(stream_id);
+        }
+        Ok(())
+    }
+
+    fn handle_data(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let stream_id = frame.stream_id;
+        if stream_id == 0 {
+            return Err(ConnectionError(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        // the whole frame counts against flow control, padding included. Bodies are
+        // bounded by max_body_size instead, so the window is handed straight back.
+        let length = frame.payload.len() as u32;
+        let data = strip_padding(frame.flags, &frame.payload)?;
+        let end_stream = frame.flags & END_STREAM != 0;
+        if length > 0 {
+            self.frame(WINDOW_UPDATE, 0, 0, &length.to_be_bytes());
+        }
+
+        let max_body_size = self.settings.max_body_size;
+        let Some(state) = self.streams.get_mut(&stream_id) else {
+            if stream_id > self.last_stream_id {
+                return Err(ConnectionError(PROTOCOL_ERROR, "DATA on idle stream"));
+            }
+            // the stream was reset, the client may not have noticed yet
+            return Ok(());
+        };
+        if state.recv_closed {
+            self.reset(stream_id, STREAM_CLOSED);
+            return Ok(());
+        }
+        state.recv_closed = end_stream;
+        let mut too_large = false;
+        if let Some(request) = &mut state.request {
+            if request.body.len() + data.len() > max_body_size {
+                too_large = !request.too_large;
+                request.too_large = true;
+                request.body = Vec::new();
+            } else if !request.too_large {
+                request.body.extend_from_slice(data);
+            }
+        }
+        if length > 0 && !end_stream {
+            self.frame(WINDOW_UPDATE, 0, stream_id, &length.to_be_bytes());
+        }
+        if too_large {
+            // answer right away instead of waiting for the rest of the body
+            if let Some(state) = self.streams.get_mut(&stream_id) {
+                state.request = None;
+            }
+            self.respond_with_error(stream_id, &ParseError::BodyTooLarge);
+        } else if end_stream {
+            self.dispatch(stream_id);
+        }
+        Ok(())
+    }
+
+    fn handle_window_update(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if frame.payload.len() != 4 {
+            return Err(ConnectionError(FRAME_SIZE_ERROR, "invalid WINDOW_UPDATE"));
+        }
+        let increment =
+            i64::from(u32::from_be_bytes(frame.payload[..4].try_into().unwrap()) & 0x7FFF_FFFF);
+        if frame.stream_id == 0 {
+            if increment == 0 {
+                return Err(ConnectionError(PROTOCOL_ERROR, "zero WINDOW_UPDATE"));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW_SIZE {
+                return Err(ConnectionError(FLOW_CONTROL_ERROR, "window too large"));
+            }
+            return Ok(());
+        }
+        let Some(state) = self.streams.get_mut(&frame.stream_id) else {
+            if frame.stream_id > self.last_stream_id {
+                return Err(ConnectionError(
+                    PROTOCOL_ERROR,
+                    "WINDOW_UPDATE on idle stream",
+                ));
+            }
+            return Ok(());
+        };
+        state.send_window += increment;
+        if increment == 0 {
+            self.reset(frame.stream_id, PROTOCOL_ERROR);
+        } else if state.send_window > MAX_WINDOW_SIZE {
+            self.reset(frame.stream_id, FLOW_CONTROL_ERROR);
+        }
+        Ok(())
+    }
+
+    /// Turns a fully received request into a [`Request`] and starts its handler.
+    fn dispatch(&mut self, stream_id: u32) {
+        let Some(pending) = self
+            .streams
+            .get_mut(&stream_id)
+            .and_then(|state| state.request.take())
+        else {
+            return;
+        };
+        match build_request(pending) {
+            Some(request) => self.respond(stream_id, request),
+            None => self.reset(stream_id, PROTOCOL_ERROR),
+        }
+    }
+
+    fn respond(&mut self, stream_id: u32, request: Request) {
+        let (router, settings) = (self.router, self.settings);
+        let head_only = request.method == RequestMethod::Head;
+        self.spawn_response(stream_id, head_only, move || {
+            let mut response = App::handle_request(router, request, settings);
+            // HTTP/2 has no 101, switching protocols only works on HTTP/1.1 connections
+            if response.take_upgrade().is_some() || response.get_status() == 101 {
+                log::warn!("Refusing to switch protocols on an HTTP/2 stream");
+                return Response::new()
+                    .set_status(505)
+                    .set_body("Switching protocols requires HTTP/1.1");
+            }
+            response
+        });
+    }
+
+    fn respond_with_error(&mut self, stream_id: u32, err: &ParseError) {
+        log::warn!("Rejecting malformed request: {err}");
+        let response = parse_error_response(err, self.settings);
+        self.spawn_response(stream_id, false, move || response);
+    }
+
+    fn spawn_response<F>(&mut self, stream_id: u32, head_only: bool, response: F)
+    where
+        F: FnOnce() -> Response + Send + 'scope,
+    {
+        let Some(state) = self.streams.get_mut(&stream_id) else {
+            return;
+        };
+        let (sender, receiver) = mpsc::sync_channel(OUTPUT_QUEUE_DEPTH);
+        state.response = Some(receiver);
+        self.running.fetch_add(1, Ordering::SeqCst);
+        let sender = StreamSender {
+            output: Some(sender),
+            wake: self.wake.clone(),
+            running: self.running.clone(),
+        };
+        self.scope
+            .spawn(move || send_response(response(), head_only, &sender));
+    }
+
+    /// Moves the output handlers queued up into frames, as far as flow control allows.
+    fn send_responses(&mut self) {
+        let stream_ids = self.streams.keys().copied().collect::<Vec<u32>>();
+        for stream_id in stream_ids {
+            while let Some(state) = self.streams.get_mut(&stream_id) {
+                if let Some((data, sent)) = &mut state.pending {
+                    let window = self.send_window.min(state.send_window).max(0) as usize;
+                    let length = (data.len() - *sent)
+                        .min(window)
+                        .min(self.peer_max_frame_size);
+                    if length == 0 {
+                        break;
+                    }
+                    let chunk = data[*sent..*sent + length].to_vec();
+                    *sent += length;
+                    if *sent == data.len() {
+                        state.pending = None;
+                    }
+                    state.send_window -= length as i64;
+                    self.send_window -= length as i64;
+                    self.frame(DATA, 0, stream_id, &chunk);
+                    continue;
+                }
+                let Some(receiver) = &state.response else {
+                    break;
+                };
+                let output = match receiver.try_recv() {
+                    Ok(output) => output,
+                    Err(TryRecvError::Empty) => break,
+                    // the handler thread died without finishing the response
+                    Err(TryRecvError::Disconnected) => Output::Reset(INTERNAL_ERROR),
+                };
+                match output {
+                    Output::Head {
+                        status,
+                        headers,
+                        end_stream,
+                    } => {
+                        let status = status.to_string();
+                        let block = hpack::encode(
+                            std::iter::once((":status", status.as_str())).chain(
+                                headers
+                                    .iter()
+                                    .map(|(name, value)| (name.as_str(), value.as_str())),
+                            ),
+                        );
+                        self.header_frames(stream_id, &block, end_stream);
+                        if end_stream {
+                            self.finish(stream_id);
+                        }
+                    }
+                    Output::Data(data) => state.pending = Some((data, 0)),
+                    Output::Trailers(trailers) => {
+                        let block = hpack::encode(
+                            trailers
+                                .iter()
+                                .map(|(name, value)| (name.as_str(), value.as_str())),
+                        );
+                        self.header_frames(stream_id, &block, true);
+                        self.finish(stream_id);
+                    }
+                    Output::End => {
+                        self.frame(DATA, END_STREAM, stream_id, &[]);
+                        self.finish(stream_id);
+                    }
+                    Output::Reset(code) => self.reset(stream_id, code),
+                }
+            }
+        }
+    }
+
+    /// Closes a stream whose response was sent completely.
+    fn finish(&mut self, stream_id: u32) {
+        if let Some(state) = self.streams.remove(&stream_id)
+            && !state.recv_closed
+        {
+            // the response didn't need the rest of the request
+            self.frame(RST_STREAM, 0, stream_id, &NO_ERROR.to_be_bytes());
+        }
+    }
+
+    fn reset(&mut self, stream_id: u32, code: u32) {
+        self.streams.remove(&stream_id);
+        self.frame(RST_STREAM, 0, stream_id, &code.to_be_bytes());
+    }
+
+    /// Tells the client no streams past the last one seen will be served.
+    fn goaway(&mut self, code: u32) {
+        self.going_away = true;
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        self.frame(GOAWAY, 0, 0, &payload);
+    }
+
+    fn header_frames(&mut self, stream_id: u32, block: &[u8], end_stream: bool) {
+        let end_stream = if end_stream { END_STREAM } else { 0 };
+        let mut fragments = block.chunks(self.peer_max_frame_size).peekable();
+        let first = fragments.next().unwrap_or_default();
+        let end_headers = if fragments.peek().is_none() {
+            END_HEADERS
+        } else {
+            0
+        };
+        self.frame(HEADERS, end_stream | end_headers, stream_id, first);
+        while let Some(fragment) = fragments.next() {
+            let end_headers = if fragments.peek().is_none() {
+                END_HEADERS
+            } else {
+                0
+            };
+            self.frame(CONTINUATION, end_headers, stream_id, fragment);
+        }
+    }
+
+    fn frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+        write_frame(&mut self.output, kind, flags, stream_id, payload);
+    }
+}
+
+fn write_frame(output: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    output.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    output.push(kind);
+    output.push(flags);
+    output.extend_from_slice(&stream_id.to_be_bytes());
+    output.extend_from_slice(payload);
+}
+
+fn strip_padding(flags: u8, payload: &[u8]) -> Result<&[u8], ConnectionError> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    match payload.split_first() {
+        Some((&padding, rest)) if usize::from(padding) <= rest.len() => {
+            Ok(&rest[..rest.len() - usize::from(padding)])
+        }
+        _ => Err(ConnectionError(PROTOCOL_ERROR, "invalid padding")),
+    }
+}
+
+/// Checks the decoded header lists of a request and builds the [`Request`], `None` if
+/// it is malformed.
+fn build_request(pending: PendingRequest) -> Option<Request> {
+    let (mut method, mut scheme, mut path, mut authority) = (None, None, None, None);
+    let mut headers = HashMap::new();
+    let mut trailers = HashMap::new();
+    let mut regular = false;
+    let join = |map: &mut HashMap<String, String>, name: String, value: String| {
+        let separator = if name == "cookie" { "; " } else { ", " };
+        map.entry(name)
+            .and_modify(|existing: &mut String| {
+                existing.push_str(separator);
+                existing.push_str(&value);
+            })
+            .or_insert(value);
+    };
+    for (name, value) in pending.headers {
+        if let Some(pseudo) = name.strip_prefix(':') {
+            // pseudo-headers come first and only once
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                _ => return None,
+            };
+            if regular || slot.is_some() {
+                return None;
+            }
+            *slot = Some(value);
+            continue;
+        }
+        regular = true;
+        if !valid_field_name(&name)
+            || CONNECTION_HEADERS.contains(&name.as_str())
+            || (name == "te" && value != "trailers")
+        {
+            return None;
+        }
+        join(&mut headers, name, value);
+    }
+    for (name, value) in pending.trailers {
+        if name.starts_with(':') || !valid_field_name(&name) {
+            return None;
+        }
+        join(&mut trailers, name, value);
+    }
+    let (method, _scheme, path) = (method?, scheme?, path?);
+    if path.is_empty() || !request::is_token(&method) {
+        return None;
+    }
+    if let Some(content_length) = headers.get("content-length")
+        && content_length.parse::<usize>().ok() != Some(pending.body.len())
+        && !pending.too_large
+    {
+        return None;
+    }
+    if let Some(authority) = authority {
+        headers.entry("host".to_string()).or_insert(authority);
+    }
+    Some(Request::from_parts(
+        &method,
+        path,
+        "HTTP/2.0".to_string(),
+        headers,
+        pending.body,
+        trailers,
+    ))
+}
+
+fn valid_field_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() && !byte.is_ascii_uppercase())
+}
+
+/// Runs on the handler thread, feeding `response` to the connection piece by piece.
+/// Responses to `HEAD` requests stop after the headers.
+fn send_response(response: Response, head_only: bool, sender: &StreamSender) {
+    let (status, headers, body, trailers) = response.into_parts();
+    let no_body = matches!(status, 100..=199 | 204 | 304);
+    let headers = headers
+        .into_iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value))
+        .filter(|(name, _)| !CONNECTION_HEADERS.contains(&name.as_str()))
+        .filter(|(name, _)| !(no_body && name == "content-length"))
+        .collect();
+    let end_stream = no_body || head_only || (body.is_empty() && trailers.is_none());
+    let head = Output::Head {
+        status,
+        headers,
+        end_stream,
+    };
+    if sender.send(head).is_err() || end_stream {
+        return;
+    }
+    let mut writer = ChannelWriter(sender);
+    if let Err(err) = body.write_to(&mut writer, Framing::Length, None) {
+        if err.kind() != io::ErrorKind::BrokenPipe {
+            log::warn!("Failed to send response body: {err}");
+        }
+        let _ = sender.send(Output::Reset(INTERNAL_ERROR));
+        return;
+    }
+    let end = match trailers {
+        Some(trailers) => Output::Trailers(
+            trailers()
+                .into_iter()
+                .map(|(name, value)| (name.to_ascii_lowercase(), value))
+                .collect(),
+        ),
+        None => Output::End,
+    };
+    let _ = sender.send(end);
+}
+
+/// The handler side of a stream, queueing its output and waking the connection up to
+/// send it.
+struct StreamSender {
+    output: Option<SyncSender<Output>>,
+    wake: SyncSender<Event>,
+    running: Arc<AtomicUsize>,
+}
+
+impl StreamSender {
+    fn send(&self, output: Output) -> Result<(), SendError<Output>> {
+        self.output
+            .as_ref()
+            .expect("the stream sender is only taken when dropped")
+            .send(output)?;
+        let _ = self.wake.send(Event::Output);
+        Ok(())
+    }
+}
+
+impl Drop for StreamSender {
+    fn drop(&mut self) {
+        // the connection has to find the output closed once it wakes up, a handler
+        // that stopped short of the end gets its stream reset
+        drop(self.output.take());
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        let _ = self.wake.send(Event::Output);
+    }
+}
+
+/// Hands body bytes written by `Body::write_to` to the connection.
+struct ChannelWriter<'a>(&'a StreamSender);
+
+impl Write for ChannelWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.0
+            .send(Output::Data(buf.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "stream closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DATA, HEADERS, PendingRequest, build_request, strip_padding, write_frame};
+    use crate::request::RequestMethod;
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn pending(headers: &[(&str, &str)], body: &[u8], trailers: &[(&str, &str)]) -> PendingRequest {
+        PendingRequest {
+            headers: fields(headers),
+            body: body.to_vec(),
+            trailers: fields(trailers),
+            too_large: false,
+        }
+    }
+
+    #[test]
+    fn writes_frame_headers() {
+        let mut output = Vec::new();
+        write_frame(&mut output, HEADERS, 0x5, 3, b"abc");
+        assert_eq!(output, b"\x00\x00\x03\x01\x05\x00\x00\x00\x03abc");
+        output.clear();
+        write_frame(&mut output, DATA, 0, 1, &[0; 300]);
+        assert_eq!(&output[..9], b"\x00\x01\x2c\x00\x00\x00\x00\x00\x01");
+    }
+
+    #[test]
+    fn strips_padding() {
+        assert_eq!(strip_padding(0, b"\x02ab").ok().unwrap(), b"\x02ab");
+        assert_eq!(strip_padding(0x8, b"\x02abcd").ok().unwrap(), b"ab");
+        assert!(strip_padding(0x8, b"\x05ab").is_err());
+        assert!(strip_padding(0x8, b"").is_err());
+    }
+
+    #[test]
+    fn builds_requests_from_pseudo_headers() {
+        let request = build_request(pending(
+            &[
+                (":method", "POST"),
+                (":scheme", "http"),
+                (":path", "/users?page=2"),
+                (":authority", "example.com"),
+                ("cookie", "a=1"),
+                ("cookie", "b=2"),
+                ("content-length", "4"),
+            ],
+            b"body",
+            &[("checksum", "abc")],
+        ))
+        .unwrap();
+        assert!(request.method == RequestMethod::Post);
+        assert_eq!(request.path, "/users");
+        assert_eq!(request.query["page"], ["2"]);
+        assert_eq!(request.version, "HTTP/2.0");
+        assert_eq!(request.header("Host"), Some("example.com"));
+        assert_eq!(request.header("Cookie"), Some("a=1; b=2"));
+        assert_eq!(request.trailers.get("checksum").unwrap(), "abc");
+        assert_eq!(request.body, b"body");
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let base = [(":method", "GET"), (":scheme", "http"), (":path", "/")];
+        assert!(build_request(pending(&base, b"", &[])).is_some());
+        // missing pseudo-header
+        assert!(build_request(pending(&base[..2], b"", &[])).is_none());
+        // pseudo-header after a regular one
+        let late = [&base[..2], &[("accept", "*/*"), (":path", "/")]].concat();
+        assert!(build_request(pending(&late, b"", &[])).is_none());
+        for header in [
+            ("Accept", "*/*"),
+            ("connection", "keep-alive"),
+            ("te", "gzip"),
+            ("content-length", "3"),
+        ] {
+            let request = [&base[..], &[header]].concat();
+            assert!(
+                build_request(pending(&request, b"", &[])).is_none(),
+                "{header:?}"
+            );
+        }
+    }
+}
@@ -1,21 +1,49 @@
+mod base64;
+pub mod body;
+mod chunked;
+mod connection;
+pub mod extensions;
+mod hpack;
+mod http2;
+mod http_date;
+mod huffman;
+pub mod middleware;
+mod pool;
 pub mod request;
 pub mod response;
 mod route_matcher;
+mod server;
+mod sha1;
+pub mod sse;
+mod static_files;
+#[cfg(feature = "tls")]
+mod tls;
+pub mod websocket;
 
 use std::{
-    collections::HashMap,
+    any::Any,
+    collections::{HashMap, HashSet},
     error::Error,
     io::Write,
     net::TcpStream,
+    panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
-    thread::{self},
+    thread,
+    time::Duration,
 };
 
-use flate2::{Compression, write::GzEncoder};
-use request::{Request, RequestMethod};
-use response::Response;
-use route_matcher::{Node, PathParams};
+use connection::ConnectionSettings;
+use extensions::Extensions;
+use middleware::{Middleware, Next};
+use pool::WorkerPool;
+use request::{DEFAULT_MAX_BODY_SIZE, ParseError, Request, RequestMethod};
+use response::{IntoResponse, Response};
+use route_matcher::{Node, PathParams, pattern_key};
+use server::ConnectionGuard;
+pub use server::{Server, ServerHandle};
+use static_files::static_handler;
+use websocket::WebSocket;
 
 use std::net::TcpListener;
 
@@ -23,9 +51,36 @@ pub type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync + 'stati
 
 pub type RequestHandler = dyn Fn(&Request, Response) -> Response + Send + Sync + 'static;
 
+pub type WebSocketHandler = dyn Fn(&Request, WebSocket) + Send + Sync + 'static;
+
+pub type ParseErrorHandler = dyn Fn(&ParseError, Response) -> Response + Send + Sync + 'static;
+
+pub type PanicHandler =
+    dyn Fn(&Request, &HandlerPanic, Response) -> Response + Send + Sync + 'static;
+
+/// Describes a panic caught while running a route handler.
+#[derive(Debug, Clone)]
+pub struct HandlerPanic {
+    /// The url pattern of the route whose handler panicked.
+    pub route: String,
+    /// The panic payload, when it was a string.
+    pub message: String,
+}
+
+/// A route handler together with the middleware registered for that route only.
+pub(crate) struct Route {
+    pub handler: Box<RequestHandler>,
+    pub middleware: Vec<Arc<Middleware>>,
+}
+
 pub struct Router {
-    pub(crate) routes: HashMap<RequestMethod, HashMap<String, Box<RequestHandler>>>,
+    pub(crate) routes: HashMap<RequestMethod, HashMap<String, Route>>,
+    // literal routes have to be inserted before params matching them, so routes are
+    // moved into other routers and matchers in the order they were registered
+    registered: Vec<(RequestMethod, String)>,
     pub(crate) roots: HashMap<RequestMethod, Node>,
+    pub(crate) middleware: Vec<Arc<Middleware>>,
+    case_sensitive: bool,
 }
 
 impl Default for Router {
@@ -38,36 +93,174 @@ impl Router {
     pub fn new() -> Self {
         Router {
             routes: HashMap::new(),
+            registered: Vec::new(),
             roots: HashMap::new(),
+            middleware: Vec::new(),
+            case_sensitive: false,
         }
     }
 
-    pub fn route<F>(&mut self, method: RequestMethod, url_pattern: &str, handler: F)
+    /// Whether literal url segments have to match the case of the route pattern. Off by
+    /// default, so `/Users` is answered by the `/users` route. Path param values keep
+    /// the case they were sent in either way.
+    ///
+    /// Panics if turning case sensitivity off leaves two routes that only differ in
+    /// case, like `/Users` and `/users`, since either of them could answer.
+    pub fn set_case_sensitive(&mut self, case_sensitive: bool) {
+        let mut roots = HashMap::new();
+        let mut keys = HashSet::new();
+        for (method, url_pattern) in &self.registered {
+            if !keys.insert((method, pattern_key(url_pattern, case_sensitive))) {
+                panic!("A handler has already been defined for this url pattern");
+            }
+            roots
+                .entry(method.clone())
+                .or_insert_with(|| Router::root(case_sensitive))
+                .append(url_pattern);
+        }
+        self.case_sensitive = case_sensitive;
+        self.roots = roots;
+    }
+
+    fn root(case_sensitive: bool) -> Node {
+        if case_sensitive {
+            Node::new_case_sensitive()
+        } else {
+            Node::new()
+        }
+    }
+
+    pub fn route<F, R>(&mut self, method: RequestMethod, url_pattern: &str, handler: F)
     where
-        F: Fn(&Request, Response) -> Response + Send + Sync + 'static,
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
     {
-        let node = self.roots.entry(method.clone()).or_default();
+        self.insert(
+            method,
+            url_pattern,
+            Route {
+                handler: Box::new(move |request, response| {
+                    handler(request, response).into_response()
+                }),
+                middleware: Vec::new(),
+            },
+        );
+    }
+
+    fn insert(&mut self, method: RequestMethod, url_pattern: &str, route: Route) {
+        let case_sensitive = self.case_sensitive;
+        let node = self
+            .roots
+            .entry(method.clone())
+            .or_insert_with(|| Router::root(case_sensitive));
         if node.find_match(url_pattern).is_some() {
             panic!("A handler has already been defined for this url pattern");
         }
-
         self.routes
-            .entry(method)
+            .entry(method.clone())
             .or_default()
-            .insert(url_pattern.to_string(), Box::new(handler));
+            .insert(url_pattern.trim().to_string(), route);
+        self.registered
+            .push((method, url_pattern.trim().to_string()));
         node.append(url_pattern);
     }
 
+    /// Mounts every route of `router` under `prefix`, so `/users` nested under `/api/v1`
+    /// answers `/api/v1/users`. The middleware added to `router` with [`Router::wrap`]
+    /// runs around its routes only, inside of the middleware of this router.
+    pub fn nest(&mut self, prefix: &str, mut router: Router) {
+        let prefix = prefix.trim().trim_end_matches('/');
+        for (method, url_pattern) in router.registered {
+            let mut route = router
+                .routes
+                .get_mut(&method)
+                .and_then(|routes| routes.remove(&url_pattern))
+                .unwrap();
+            let url_pattern = match url_pattern.trim_start_matches('/') {
+                "" if prefix.is_empty() => "/".to_string(),
+                "" => prefix.to_string(),
+                url_pattern => format!("{prefix}/{url_pattern}"),
+            };
+            route.middleware = router
+                .middleware
+                .iter()
+                .cloned()
+                .chain(route.middleware)
+                .collect();
+            self.insert(method, &url_pattern, route);
+        }
+    }
+
+    /// Runs `middleware` around every request, matched by a route or not. Once the
+    /// router is nested in another one, around the requests for its own routes.
+    pub fn wrap<F>(&mut self, middleware: F)
+    where
+        F: Fn(Request, Next) -> Response + Send + Sync + 'static,
+    {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    /// Runs `middleware` around the handler registered for `method` and `url_pattern`,
+    /// inside of the middleware added with [`Router::wrap`].
+    pub fn wrap_route<F>(&mut self, method: RequestMethod, url_pattern: &str, middleware: F)
+    where
+        F: Fn(Request, Next) -> Response + Send + Sync + 'static,
+    {
+        let route = self
+            .routes
+            .get_mut(&method)
+            .and_then(|routes| routes.get_mut(url_pattern.trim()))
+            .expect("No handler has been defined for this url pattern");
+        route.middleware.push(Arc::new(middleware));
+    }
+
+    /// Methods with a route matching `url`, sorted by name, counting the `HEAD` and
+    /// `OPTIONS` requests answered without one. Used to tell a wrong method (405) apart
+    /// from an unknown path (404).
+    pub(crate) fn allowed_methods(&self, url: &str) -> Vec<RequestMethod> {
+        let mut methods = self
+            .roots
+            .iter()
+            .filter(|(_, node)| node.find_match(url).is_some())
+            .map(|(method, _)| method.clone())
+            .collect::<Vec<RequestMethod>>();
+        if methods.is_empty() {
+            return methods;
+        }
+        if methods.contains(&RequestMethod::Get) {
+            methods.push(RequestMethod::Head);
+        }
+        methods.push(RequestMethod::Options);
+        methods.sort_by_key(|method| method.to_string());
+        methods.dedup();
+        methods
+    }
+
+    /// Finds the route for `method` and `url`. `HEAD` requests without a route of their
+    /// own run the `GET` handler, the response is sent without its body.
     pub(crate) fn get_handler(
         &self,
         method: &RequestMethod,
         url: &str,
-    ) -> Option<(&RequestHandler, PathParams)> {
+    ) -> Option<(&Route, String, PathParams)> {
+        if *method == RequestMethod::Head {
+            let head = self.find_route(method, url);
+            return head.or_else(|| self.find_route(&RequestMethod::Get, url));
+        }
+        self.find_route(method, url)
+    }
+
+    fn find_route(
+        &self,
+        method: &RequestMethod,
+        url: &str,
+    ) -> Option<(&Route, String, PathParams)> {
         let node = self.roots.get(method)?;
         if let Some((url_pattern, path_params)) = node.find_match(url) {
             log::trace!("Matched route for: {url_pattern}");
             return Some((
                 self.routes.get(method).unwrap().get(&url_pattern).unwrap(),
+                url_pattern,
                 path_params,
             ));
         }
@@ -79,6 +272,18 @@ pub struct App {
     router: Arc<RwLock<Router>>,
     port: u16,
     listen_ip: String,
+    max_body_size: usize,
+    keep_alive_timeout: Duration,
+    max_requests_per_connection: usize,
+    workers: usize,
+    queue_depth: usize,
+    max_long_lived_connections: usize,
+    handle: ServerHandle,
+    on_parse_error: Option<Arc<ParseErrorHandler>>,
+    on_panic: Option<Arc<PanicHandler>>,
+    state: Extensions,
+    #[cfg(feature = "tls")]
+    tls: Option<tls::TlsSettings>,
 }
 
 impl Default for App {
@@ -89,98 +294,253 @@ impl Default for App {
 
 impl App {
     pub fn new() -> Self {
+        let mut router = Router::new();
+        router.wrap(middleware::gzip);
         Self {
-            router: Arc::new(RwLock::new(Router::new())),
+            router: Arc::new(RwLock::new(router)),
             port: 0,
             listen_ip: "0.0.0.0".into(),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            keep_alive_timeout: Duration::from_secs(5),
+            max_requests_per_connection: 100,
+            workers: 16,
+            queue_depth: 64,
+            max_long_lived_connections: 256,
+            handle: ServerHandle::default(),
+            on_parse_error: None,
+            on_panic: None,
+            state: Extensions::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 
-    pub(crate) fn is_compression_supported(compression_str: &str) -> Option<String> {
-        let str_parts = compression_str
-            .split(",")
-            .map(|v| v.trim())
-            .collect::<Vec<&str>>();
-        for compression_scheme in str_parts {
-            if compression_scheme.eq_ignore_ascii_case("gzip") {
-                return Some(compression_scheme.to_string());
-            }
-        }
-        None
-    }
-
-    pub(crate) fn compress(input: &[u8], _compression_type: &str) -> Result<Vec<u8>> {
-        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-
-        encoder.write_all(input)?;
-        encoder.flush()?;
-        let buffer = encoder.finish()?;
-        Ok(buffer)
-    }
-
-    pub(crate) fn get_response<F>(request: Request, handler: F) -> Response
+    pub(crate) fn get_response<F>(
+        request: Request,
+        route: &str,
+        handler: F,
+        on_panic: Option<&PanicHandler>,
+    ) -> Response
     where
         F: Fn(&Request, Response) -> Response + Send + Sync,
     {
-        let compression = match request.headers.get("Accept-Encoding") {
-            Some(v) => App::is_compression_supported(v),
-            None => None,
-        };
-
-        let response = handler(&request, Response::new());
-        let response = match compression {
-            Some(v) => {
-                if let Ok(compressed) = App::compress(response.get_body().as_slice(), &v) {
-                    return response
-                        .add_header("Content-Encoding", "gzip")
-                        .set_body_bytes(&compressed);
+        match panic::catch_unwind(AssertUnwindSafe(|| handler(&request, Response::new()))) {
+            Ok(response) => response,
+            Err(payload) => {
+                let handler_panic = HandlerPanic {
+                    route: route.to_string(),
+                    message: panic_message(payload.as_ref()),
+                };
+                log::error!(
+                    "Handler for {} {} panicked: {}",
+                    request.method,
+                    handler_panic.route,
+                    handler_panic.message
+                );
+                let response = Response::new().set_status(500);
+                match on_panic {
+                    Some(on_panic) => on_panic(&request, &handler_panic, response),
+                    None => response.set_body("Internal Server Error"),
                 }
-                response
             }
-            None => response,
-        };
-        response
+        }
     }
 
-    fn request_handler(&self, mut stream: TcpStream) -> Result<()> {
-        let router = self.router.clone();
-        thread::spawn(move || {
-            let mut request = Request::try_from(&mut stream).unwrap();
-
-            let path = request.path.clone();
-            let method = request.method.clone();
-            let response = if let Some((handler, path_params)) =
-                router.read().unwrap().get_handler(&method, &path)
-            {
+    pub(crate) fn handle_request(
+        router: &RwLock<Router>,
+        mut request: Request,
+        settings: &ConnectionSettings,
+    ) -> Response {
+        let path = request.path.clone();
+        let method = request.method.clone();
+        request.extensions = settings.state.clone();
+        let router = router.read().unwrap();
+        let response =
+            if let Some((route, url_pattern, path_params)) = router.get_handler(&method, &path) {
                 request.path_params = path_params;
-                App::get_response(request, handler)
+                let middleware = router
+                    .middleware
+                    .iter()
+                    .chain(&route.middleware)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let endpoint = |request| {
+                    App::get_response(
+                        request,
+                        &url_pattern,
+                        &route.handler,
+                        settings.on_panic.as_deref(),
+                    )
+                };
+                Next::new(&middleware, &endpoint).run(request)
             } else {
-                Response::new().set_status(404)
+                let allowed = router.allowed_methods(&path);
+                let endpoint = |_request| {
+                    if allowed.is_empty() {
+                        return Response::new().set_status(404);
+                    }
+                    let allow = allowed
+                        .iter()
+                        .map(RequestMethod::to_string)
+                        .collect::<Vec<String>>()
+                        .join(", ");
+                    // without a handler of its own, OPTIONS lists what the path supports
+                    let status = if method == RequestMethod::Options {
+                        204
+                    } else {
+                        405
+                    };
+                    Response::new()
+                        .set_status(status)
+                        .add_header("Allow", &allow)
+                };
+                Next::new(&router.middleware, &endpoint).run(request)
             };
-            log::info!(
-                "{} {} {} {}",
-                method,
-                path,
-                response.get_status(),
-                response.get_body().len(),
-            );
+        log::info!(
+            "{} {} {} {}",
+            method,
+            path,
+            response.get_status(),
+            response
+                .body_length()
+                .map_or_else(|| "-".to_string(), |length| length.to_string()),
+        );
+        response
+    }
+
+    fn worker_pool(&self) -> WorkerPool<(TcpStream, ConnectionGuard)> {
+        let router = self.router.clone();
+        let settings = ConnectionSettings {
+            max_body_size: self.max_body_size,
+            keep_alive_timeout: self.keep_alive_timeout,
+            max_requests: self.max_requests_per_connection,
+            max_long_lived: self.max_long_lived_connections,
+            on_parse_error: self.on_parse_error.clone(),
+            on_panic: self.on_panic.clone(),
+            state: self.state.clone(),
+        };
+        #[cfg(feature = "tls")]
+        let tls = self.handle.state.tls_acceptor();
+        WorkerPool::new(
+            self.workers,
+            self.queue_depth,
+            move |(stream, guard): (TcpStream, ConnectionGuard)| {
+                if let Err(err) = stream.set_read_timeout(Some(settings.keep_alive_timeout)) {
+                    log::error!("Failed to configure connection: {err}");
+                    return;
+                }
+                #[cfg(feature = "tls")]
+                if let Some(tls) = &tls {
+                    match tls.accept(stream) {
+                        Ok(stream) => connection::serve(stream, &router, &settings, guard),
+                        Err(err) => log::error!("Failed to start a TLS session: {err}"),
+                    }
+                    return;
+                }
+                connection::serve(stream, &router, &settings, guard);
+            },
+        )
+    }
+
+    fn request_handler(
+        pool: &WorkerPool<(TcpStream, ConnectionGuard)>,
+        stream: TcpStream,
+        guard: ConnectionGuard,
+        plaintext: bool,
+    ) {
+        if let Err((mut stream, _)) = pool.try_dispatch((stream, guard)) {
+            log::warn!("All workers are busy, rejecting connection");
+            if !plaintext {
+                // a response can't be sent before the TLS handshake, just hang up
+                return;
+            }
+            let response = Response::new()
+                .set_status(503)
+                .add_header("Retry-After", "1")
+                .add_header("Connection", "close")
+                .set_body("Service Unavailable");
+            // never let a slow client hold up the accept loop
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
             let _ = stream.write_all(response.build().as_slice());
-        });
+        }
+    }
+
+    fn bind(&self) -> std::io::Result<TcpListener> {
+        let listener = TcpListener::bind(format!("{}:{}", self.listen_ip, self.port))?;
+        let local_addr = listener.local_addr()?;
+        self.handle.state.set_local_addr(local_addr);
+        log::info!("Listening on: {local_addr}");
+        Ok(listener)
+    }
+
+    #[cfg(feature = "tls")]
+    fn start_tls(&self) -> Result<()> {
+        if let Some(settings) = &self.tls {
+            let acceptor = tls::TlsAcceptor::new(settings.clone())?;
+            self.handle.state.set_tls_acceptor(acceptor);
+        }
         Ok(())
     }
 
-    pub fn run(self) -> Result<()> {
-        let listener = TcpListener::bind(format!("{}:{}", self.listen_ip, self.port))
-            .expect("Failed to create TCP Socket");
+    #[cfg(not(feature = "tls"))]
+    fn start_tls(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_plaintext(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_none();
+        #[cfg(not(feature = "tls"))]
+        true
+    }
 
-        log::info!("Listening on: {}:{}", self.listen_ip, self.port);
+    fn accept_loop(self, listener: TcpListener) -> Result<()> {
+        let pool = self.worker_pool();
+        let plaintext = self.is_plaintext();
         for stream in listener.incoming() {
+            if self.handle.is_shutting_down() {
+                break;
+            }
             let stream = stream?;
-            self.request_handler(stream)?;
+            let guard = self.handle.state.track(&stream)?;
+            App::request_handler(&pool, stream, guard, plaintext);
+        }
+        // dropping the pool lets the workers exit once the queued connections are served
+        drop(pool);
+        // bounded by the timeout given to `shutdown_with_timeout`, stuck handlers are
+        // left behind on their workers
+        if !self.handle.state.wait_for_connections() {
+            self.handle.state.abandon_connections();
         }
+        log::info!("Server stopped");
         Ok(())
     }
 
+    pub fn run(self) -> Result<()> {
+        self.start_tls()?;
+        let listener = self.bind().expect("Failed to create TCP Socket");
+        self.accept_loop(listener)
+    }
+
+    /// Binds the listener and serves connections on a background thread. The returned
+    /// [`Server`] reports the bound address, which is handy when the port is 0 and the
+    /// OS picks one.
+    pub fn spawn(self) -> Result<Server> {
+        self.start_tls()?;
+        let listener = self.bind()?;
+        let local_addr = listener.local_addr()?;
+        let handle = self.handle();
+        let thread = thread::Builder::new()
+            .name("http-accept".into())
+            .spawn(move || self.accept_loop(listener))?;
+        Ok(Server::new(local_addr, handle, thread))
+    }
+
+    /// Returns a handle that can shut the server down once it is running.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
     pub fn set_listen_ip(mut self, ip: &str) -> Self {
         self.listen_ip = ip.to_string();
         self
@@ -191,175 +551,352 @@ impl App {
         self
     }
 
-    pub fn static_folder<'a>(self, pathname: &str, folder_path: &Path) -> Self {
-        let folder_path = Arc::new(PathBuf::from(folder_path));
-        let pathname = pathname.to_string();
-        self.router.write().unwrap().route(
-            RequestMethod::Get,
-            &format!("{pathname}/*"),
-            move |request, response| {
-                static_handler(&pathname, folder_path.clone(), request, response)
-            },
-        );
+    /// Serves every connection over TLS with the PEM encoded certificate chain and
+    /// private key found at the given paths. The files are read when the server starts,
+    /// see [`ServerHandle::reload_tls`] to pick up renewed certificates.
+    #[cfg(feature = "tls")]
+    pub fn set_tls(mut self, cert_path: &Path, key_path: &Path) -> Self {
+        self.tls
+            .get_or_insert_with(Default::default)
+            .set_default(cert_path, key_path);
+        self
+    }
 
+    /// Serves clients asking for `server_name` through SNI with a certificate of its
+    /// own. Names like `*.example.com` match any single subdomain. Clients asking for
+    /// other names get the certificate from [`App::set_tls`].
+    #[cfg(feature = "tls")]
+    pub fn add_tls_certificate(
+        mut self,
+        server_name: &str,
+        cert_path: &Path,
+        key_path: &Path,
+    ) -> Self {
+        self.tls
+            .get_or_insert_with(Default::default)
+            .add_server_name(server_name, cert_path, key_path);
         self
     }
 
-    pub fn get<F>(self, path: &str, handler: F) -> Self
-    where
-        F: Fn(&Request, Response) -> Response + Send + Sync + 'static,
-    {
+    /// Limits the size of request bodies, including decoded chunked bodies.
+    pub fn set_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Makes literal path segments match only when the case is the same, `/Users` no
+    /// longer reaching the `/users` route. See [`Router::set_case_sensitive`].
+    pub fn set_case_sensitive_routes(self, case_sensitive: bool) -> Self {
         self.router
             .write()
             .unwrap()
-            .route(RequestMethod::Get, path, handler);
+            .set_case_sensitive(case_sensitive);
+        self
+    }
+
+    /// How long an idle persistent connection is kept open waiting for the next request.
+    /// The connection holds on to its worker meanwhile, see [`App::set_workers`].
+    pub fn set_keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// Maximum number of requests served on a single connection before it is closed.
+    /// Setting this to 1 disables persistent connections.
+    pub fn set_max_requests_per_connection(mut self, max_requests: usize) -> Self {
+        self.max_requests_per_connection = max_requests.max(1);
+        self
+    }
+
+    /// Number of worker threads serving connections. An HTTP/2 connection runs the
+    /// handlers of up to 8 concurrent streams on threads of their own.
+    ///
+    /// A worker stays with its HTTP/1.1 connection until it closes, including the time
+    /// spent waiting for the next request on a persistent connection. As many clients
+    /// idling between requests as there are workers keep new connections queued, and
+    /// answered with a 503 once the queue is full, until the keep-alive timeout closes
+    /// them. HTTP/2 connections, WebSockets and event streams are moved off the workers,
+    /// see [`App::set_max_long_lived_connections`].
+    pub fn set_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Number of HTTP/2 connections, WebSockets and event streams served on threads of
+    /// their own, so they don't tie up the workers for as long as they stay open. Past
+    /// this limit they keep the worker they started on.
+    pub fn set_max_long_lived_connections(mut self, max_connections: usize) -> Self {
+        self.max_long_lived_connections = max_connections;
+        self
+    }
+
+    /// Number of accepted connections that can wait for a free worker. Connections
+    /// accepted while the queue is full are answered with a 503.
+    pub fn set_queue_depth(mut self, queue_depth: usize) -> Self {
+        self.queue_depth = queue_depth;
         self
     }
 
-    pub fn post<F>(self, path: &str, handler: F) -> Self
+    /// Customises the response sent for requests that could not be parsed. The
+    /// response passed to `handler` already carries the matching status code.
+    pub fn on_parse_error<F>(mut self, handler: F) -> Self
     where
-        F: Fn(&Request, Response) -> Response + Send + Sync + 'static,
+        F: Fn(&ParseError, Response) -> Response + Send + Sync + 'static,
     {
-        self.router
-            .write()
-            .unwrap()
-            .route(RequestMethod::Post, path, handler);
+        self.on_parse_error = Some(Arc::new(handler));
         self
     }
 
-    pub fn put<F>(self, path: &str, handler: F) -> Self
+    /// Customises the 500 response sent when a route handler panics, e.g. to render an
+    /// error page or report the panic somewhere.
+    pub fn on_panic<F>(mut self, handler: F) -> Self
     where
-        F: Fn(&Request, Response) -> Response + Send + Sync + 'static,
+        F: Fn(&Request, &HandlerPanic, Response) -> Response + Send + Sync + 'static,
     {
-        self.router
-            .write()
-            .unwrap()
-            .route(RequestMethod::Put, path, handler);
+        self.on_panic = Some(Arc::new(handler));
         self
     }
 
-    pub fn delete<F>(self, path: &str, handler: F) -> Self
+    /// Makes `state` available to every handler and middleware through
+    /// [`Request::state`]. Call it once per type, a second value of the same type
+    /// replaces the first.
+    pub fn with_state<T: Send + Sync + 'static>(mut self, state: T) -> Self {
+        self.state.insert(state);
+        self
+    }
+
+    /// Runs `middleware` around every request, including the ones no route matches.
+    /// Middleware added first wraps the ones added after it, the built-in
+    /// [`middleware::gzip`] wraps them all.
+    pub fn wrap<F>(self, middleware: F) -> Self
     where
-        F: Fn(&Request, Response) -> Response + Send + Sync + 'static,
+        F: Fn(Request, Next) -> Response + Send + Sync + 'static,
     {
-        self.router
-            .write()
-            .unwrap()
-            .route(RequestMethod::Delete, path, handler);
+        self.router.write().unwrap().wrap(middleware);
         self
     }
 
-    pub fn patch<F>(self, path: &str, handler: F) -> Self
+    /// Runs `middleware` around the handler of a route that was already added, inside
+    /// of the middleware added with [`App::wrap`].
+    pub fn wrap_route<F>(self, method: RequestMethod, path: &str, middleware: F) -> Self
     where
-        F: Fn(&Request, Response) -> Response + Send + Sync + 'static,
+        F: Fn(Request, Next) -> Response + Send + Sync + 'static,
     {
         self.router
             .write()
             .unwrap()
-            .route(RequestMethod::Patch, path, handler);
+            .wrap_route(method, path, middleware);
+        self
+    }
+
+    /// Mounts every route of `router` under `prefix`. See [`Router::nest`].
+    pub fn nest(self, prefix: &str, router: Router) -> Self {
+        self.router.write().unwrap().nest(prefix, router);
         self
     }
 
-    pub fn options<F>(self, path: &str, handler: F) -> Self
+    /// Groups routes under a common `prefix`. The middleware added to the scope only
+    /// runs around the routes defined in it.
+    pub fn scope<F>(self, prefix: &str, scope: F) -> Self
     where
-        F: Fn(&Request, Response) -> Response + Send + Sync + 'static,
+        F: FnOnce(Scope) -> Scope,
     {
-        self.router
-            .write()
-            .unwrap()
-            .route(RequestMethod::Options, path, handler);
+        let scope = scope(Scope::new());
+        self.nest(prefix, scope.router)
+    }
+
+    pub fn static_folder(self, pathname: &str, folder_path: &Path) -> Self {
+        let folder_path = Arc::new(PathBuf::from(folder_path));
+        let pathname = pathname.to_string();
+        self.router.write().unwrap().route(
+            RequestMethod::Get,
+            &format!("{pathname}/*"),
+            move |request, response| {
+                static_handler(&pathname, folder_path.clone(), request, response)
+            },
+        );
+
         self
     }
+
+    /// Accepts WebSocket connections on `path`. Once the handshake is done `handler`
+    /// takes over the connection on a thread of its own, leaving the worker free, and
+    /// runs until it returns. Past [`App::set_max_long_lived_connections`] it runs on the
+    /// worker instead. Requests that aren't WebSocket upgrades are answered with 426.
+    pub fn ws<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, WebSocket) + Send + Sync + 'static,
+    {
+        let handler: Arc<WebSocketHandler> = Arc::new(handler);
+        self.get(path, move |request, response| {
+            websocket::handshake(request, response, handler.clone())
+        })
+    }
+
+    /// Routes requests with any method, including extension methods such as
+    /// `RequestMethod::from("PROPFIND")`, to `handler`.
+    pub fn route<F, R>(self, method: RequestMethod, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.router.write().unwrap().route(method, path, handler);
+        self
+    }
+
+    pub fn get<F, R>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route(RequestMethod::Get, path, handler)
+    }
+
+    pub fn post<F, R>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route(RequestMethod::Post, path, handler)
+    }
+
+    pub fn put<F, R>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route(RequestMethod::Put, path, handler)
+    }
+
+    pub fn delete<F, R>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route(RequestMethod::Delete, path, handler)
+    }
+
+    pub fn patch<F, R>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route(RequestMethod::Patch, path, handler)
+    }
+
+    pub fn options<F, R>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route(RequestMethod::Options, path, handler)
+    }
 }
 
-fn static_handler(
-    root_path: &str,
-    folder_path: Arc<PathBuf>,
-    request: &Request,
-    response: Response,
-) -> Response {
-    if !folder_path.exists() {
-        return response.set_body("Not Found").set_status(404);
+/// A group of routes sharing a path prefix and middleware, built by [`App::scope`].
+/// Paths given to a scope are relative to its prefix.
+#[derive(Default)]
+pub struct Scope {
+    router: Router,
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    if !folder_path.is_dir() {
-        return response.set_body("Resource type conflict").set_status(409);
+    pub fn route<F, R>(mut self, method: RequestMethod, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.router.route(method, path, handler);
+        self
     }
 
-    let resource = if request.path.starts_with(root_path) {
-        request.path.strip_prefix(root_path).unwrap()
-    } else {
-        request.path.as_str()
-    };
+    pub fn get<F, R>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route(RequestMethod::Get, path, handler)
+    }
 
-    let resource = if resource.starts_with("/") {
-        resource.strip_prefix("/").unwrap()
-    } else {
-        resource
-    };
+    pub fn post<F, R>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route(RequestMethod::Post, path, handler)
+    }
 
-    let mut requested_resource = PathBuf::from(&*folder_path);
+    pub fn put<F, R>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route(RequestMethod::Put, path, handler)
+    }
 
-    // determine if the request was for the index file
-    match resource {
-        "" | "/" => {
-            requested_resource = requested_resource.join("index.html");
-        }
-        _ => requested_resource = requested_resource.join(resource),
-    }
-
-    if !requested_resource.exists() {
-        return response.set_body("Not Found").set_status(404);
-    }
-
-    if !requested_resource.is_file() {
-        return response
-            .set_body("Invalid resource requested")
-            .set_status(409);
-    }
-
-    let content_type = match requested_resource.extension() {
-        Some(extension) => match extension.to_str().unwrap() {
-            "css" => "text/css",
-            "js" => "text/javascript",
-            "htm" => "text/html",
-            "html" => "text/html",
-            "xml" => "text/xml",
-            "jpg" => "image/jpeg",
-            "png" => "image/png",
-            "gif" => "image/gif",
-            "webp" => "image/webp",
-            "avif" => "image/avif",
-            "svg" => "image/svg+xml",
-            "ico" => "image/vnd.microsoft.icon",
-            _ => "application/octet-stream",
-        },
-        None => "application/octed-stream",
-    };
-
-    let response = response.add_header("Content-Type", content_type);
-
-    let response = match content_type {
-        "text/css" | "text/html" | "text/xml" | "text/svg+xml" => {
-            let read_result = std::fs::read_to_string(requested_resource);
-            if let Ok(content) = read_result {
-                response.set_body(&content).set_status(200)
-            } else {
-                response
-                    .set_body(&read_result.err().unwrap().to_string())
-                    .set_status(500)
-            }
-        }
-        _ => {
-            let read_result = std::fs::read(requested_resource);
-            if let Ok(content) = read_result {
-                response.set_body_bytes(&content).set_status(200)
-            } else {
-                response
-                    .set_body(&read_result.err().unwrap().to_string())
-                    .set_status(500)
-            }
-        }
-    };
-    response
+    pub fn delete<F, R>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route(RequestMethod::Delete, path, handler)
+    }
+
+    pub fn patch<F, R>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route(RequestMethod::Patch, path, handler)
+    }
+
+    pub fn options<F, R>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, Response) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.route(RequestMethod::Options, path, handler)
+    }
+
+    /// Runs `middleware` around the routes of this scope, nested scopes included.
+    pub fn wrap<F>(mut self, middleware: F) -> Self
+    where
+        F: Fn(Request, Next) -> Response + Send + Sync + 'static,
+    {
+        self.router.wrap(middleware);
+        self
+    }
+
+    /// Runs `middleware` around the handler of a route already added to this scope.
+    pub fn wrap_route<F>(mut self, method: RequestMethod, path: &str, middleware: F) -> Self
+    where
+        F: Fn(Request, Next) -> Response + Send + Sync + 'static,
+    {
+        self.router.wrap_route(method, path, middleware);
+        self
+    }
+
+    /// Groups routes under a prefix nested in the one of this scope.
+    pub fn scope<F>(mut self, prefix: &str, scope: F) -> Self
+    where
+        F: FnOnce(Scope) -> Scope,
+    {
+        let scope = scope(Scope::new());
+        self.router.nest(prefix, scope.router);
+        self
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
 }
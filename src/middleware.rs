@@ -0,0 +1,180 @@
+use std::{io::Write, sync::Arc};
+
+use flate2::{Compression, write::GzEncoder};
+
+use crate::{request::Request, response::Response};
+
+/// Logic that runs around route handlers. A middleware gets the request before the
+/// handler does and decides whether to pass it on through [`Next::run`], possibly
+/// changed, or to answer right away. Whatever comes back from `next` can be modified
+/// before it is returned.
+pub type Middleware = dyn Fn(Request, Next) -> Response + Send + Sync + 'static;
+
+/// The rest of the chain: the middleware that haven't run yet, followed by the route
+/// handler.
+pub struct Next<'a> {
+    middleware: &'a [Arc<Middleware>],
+    endpoint: &'a dyn Fn(Request) -> Response,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        middleware: &'a [Arc<Middleware>],
+        endpoint: &'a dyn Fn(Request) -> Response,
+    ) -> Self {
+        Self {
+            middleware,
+            endpoint,
+        }
+    }
+
+    /// Passes `request` on and returns the response the rest of the chain produced.
+    pub fn run(self, request: Request) -> Response {
+        match self.middleware.split_first() {
+            Some((middleware, rest)) => middleware(request, Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+/// Compresses response bodies with gzip for clients that accept it. Registered on
+/// every [`crate::App`] before any other middleware.
+pub fn gzip(request: Request, next: Next) -> Response {
+    let accepts_gzip = request.header("Accept-Encoding").is_some_and(|value| {
+        value
+            .split(',')
+            .any(|scheme| scheme.trim().eq_ignore_ascii_case("gzip"))
+    });
+    let response = next.run(request);
+    if !accepts_gzip
+        // byte ranges point into the uncompressed file and must be sent as they are
+        || response.get_header("Content-Range").is_some()
+        || response.get_body().is_empty()
+        || response.is_streamed()
+    {
+        return response;
+    }
+    let Ok(compressed) = compress(response.get_body()) else {
+        return response;
+    };
+    // the compressed bytes differ from the original ones, so a strong entity tag would
+    // no longer be accurate
+    let response = match response.get_header("ETag") {
+        Some(etag) if etag.starts_with('"') => {
+            let weak_etag = format!("W/{etag}");
+            response.add_header("ETag", &weak_etag)
+        }
+        _ => response,
+    };
+    response
+        .add_header("Content-Encoding", "gzip")
+        .set_body_bytes(&compressed)
+}
+
+fn compress(input: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(input)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{Middleware, Next, gzip};
+    use crate::{request::Request, response::Response};
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Request::from_parts(
+            "GET",
+            "/".to_string(),
+            "HTTP/1.1".to_string(),
+            headers,
+            vec![],
+            HashMap::new(),
+        )
+    }
+
+    #[test]
+    fn runs_middleware_in_order_around_the_endpoint() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let tracing = |name: &'static str| -> Arc<Middleware> {
+            let calls = calls.clone();
+            Arc::new(move |request: Request, next: Next| {
+                calls.lock().unwrap().push(format!("{name} before"));
+                let response = next.run(request);
+                calls.lock().unwrap().push(format!("{name} after"));
+                response
+            })
+        };
+        let middleware = [tracing("outer"), tracing("inner")];
+        let endpoint = |_request: Request| {
+            calls.lock().unwrap().push("endpoint".to_string());
+            Response::new().set_status(200)
+        };
+        let response = Next::new(&middleware, &endpoint).run(request(&[]));
+        assert_eq!(response.get_status(), 200);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "outer before",
+                "inner before",
+                "endpoint",
+                "inner after",
+                "outer after"
+            ]
+        );
+    }
+
+    #[test]
+    fn middleware_can_short_circuit_and_rewrite_requests() {
+        let middleware: [Arc<Middleware>; 2] = [
+            Arc::new(|request: Request, next: Next| {
+                if request.header("Authorization").is_none() {
+                    return Response::new().set_status(401);
+                }
+                next.run(request)
+            }),
+            Arc::new(|mut request: Request, next: Next| {
+                request.headers.insert("X-User".into(), "admin".into());
+                next.run(request).add_header("X-Checked", "yes")
+            }),
+        ];
+        let endpoint = |request: Request| {
+            Response::new()
+                .set_status(200)
+                .set_body(request.header("X-User").unwrap_or_default())
+        };
+        let response = Next::new(&middleware, &endpoint).run(request(&[]));
+        assert_eq!(response.get_status(), 401);
+        let response =
+            Next::new(&middleware, &endpoint).run(request(&[("Authorization", "Bearer x")]));
+        assert_eq!(response.get_body(), b"admin");
+        assert_eq!(response.get_header("X-Checked"), Some("yes"));
+    }
+
+    #[test]
+    fn gzip_compresses_for_clients_that_accept_it() {
+        let middleware: [Arc<Middleware>; 1] = [Arc::new(gzip)];
+        let endpoint = |_request: Request| {
+            Response::new()
+                .set_status(200)
+                .add_header("ETag", "\"abc\"")
+                .set_body(&"hello ".repeat(100))
+        };
+        let plain = Next::new(&middleware, &endpoint).run(request(&[]));
+        assert_eq!(plain.get_header("Content-Encoding"), None);
+        let compressed =
+            Next::new(&middleware, &endpoint).run(request(&[("Accept-Encoding", "deflate, gzip")]));
+        assert_eq!(compressed.get_header("Content-Encoding"), Some("gzip"));
+        assert_eq!(compressed.get_header("ETag"), Some("W/\"abc\""));
+        assert!(compressed.get_body().len() < plain.get_body().len());
+    }
+}
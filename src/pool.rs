@@ -0,0 +1,103 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    thread,
+};
+
+/// A fixed set of worker threads fed through a bounded queue.
+///
+/// Every item dispatched to the pool is handed to `handler` on one of the workers. When
+/// all workers are busy and the queue is full the item is given back to the caller,
+/// which is then responsible for shedding the load.
+pub(crate) struct WorkerPool<T> {
+    sender: SyncSender<T>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn new<F>(workers: usize, queue_depth: usize, handler: F) -> Self
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+        for id in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            let handler = handler.clone();
+            thread::Builder::new()
+                .name(format!("http-worker-{id}"))
+                .spawn(move || worker_loop(&receiver, &*handler))
+                .expect("Failed to spawn worker thread");
+        }
+        Self { sender }
+    }
+
+    /// Queues `item` for the next free worker, or returns it if the queue is full.
+    pub fn try_dispatch(&self, item: T) -> Result<(), T> {
+        match self.sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) | Err(TrySendError::Disconnected(item)) => Err(item),
+        }
+    }
+}
+
+fn worker_loop<T, F: Fn(T)>(receiver: &Mutex<Receiver<T>>, handler: &F) {
+    loop {
+        // the lock is released as soon as an item is received so other workers can wait
+        let item = match receiver.lock().unwrap().recv() {
+            Ok(item) => item,
+            Err(_) => return,
+        };
+        // a panicking connection should not take the worker down with it
+        if panic::catch_unwind(AssertUnwindSafe(|| handler(item))).is_err() {
+            log::error!(
+                "Worker {:?} recovered from a panic",
+                thread::current().name()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier, mpsc};
+
+    use super::WorkerPool;
+
+    #[test]
+    fn runs_items_on_workers() {
+        let (results, received) = mpsc::channel();
+        let pool = WorkerPool::new(2, 4, move |value: u32| results.send(value * 2).unwrap());
+        for value in 0..4 {
+            pool.try_dispatch(value).unwrap();
+        }
+        let mut doubled = received.iter().take(4).collect::<Vec<u32>>();
+        doubled.sort();
+        assert_eq!(doubled, vec![0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn gives_items_back_when_the_queue_is_full() {
+        let started = Arc::new(Barrier::new(2));
+        let release = Arc::new(Barrier::new(2));
+        let pool = {
+            let started = started.clone();
+            let release = release.clone();
+            WorkerPool::new(1, 1, move |_: u32| {
+                started.wait();
+                release.wait();
+            })
+        };
+        pool.try_dispatch(1).unwrap();
+        // the only worker is now busy with the first item
+        started.wait();
+        pool.try_dispatch(2).unwrap();
+        assert_eq!(pool.try_dispatch(3), Err(3));
+        release.wait();
+        started.wait();
+        release.wait();
+    }
+}
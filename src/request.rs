@@ -3,114 +3,710 @@ use std::{
     error::Error,
     fmt::Display,
     hash::Hash,
-    io::{BufRead, BufReader, Read},
+    io::{self, BufRead, BufReader, Read},
     net::TcpStream,
+    str::FromStr,
 };
+
+use crate::{
+    chunked::{ChunkedDecoder, parse_length},
+    extensions::Extensions,
+};
+
+/// Request bodies larger than this are rejected unless configured otherwise
+/// through `App::set_max_body_size`.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Query string values keyed by name. A key can appear more than once
+/// (`?tag=a&tag=b`) so every value is kept, in the order it was sent.
+pub type QueryParams = HashMap<String, Vec<String>>;
+
 #[derive(Clone, Eq, PartialEq, Hash)]
 pub enum RequestMethod {
     Get,
+    Head,
     Post,
     Put,
     Delete,
     Patch,
     Options,
-    Unknown,
+    Trace,
+    Connect,
+    /// Any other method, such as WebDAV's `PROPFIND` or `MKCOL`. Method names are case
+    /// sensitive, so this holds the name exactly as it was sent. Build it with
+    /// [`RequestMethod::from`] so the standard methods get their own variants.
+    Extension(String),
+}
+
+impl From<&str> for RequestMethod {
+    fn from(method: &str) -> Self {
+        match method {
+            "GET" => RequestMethod::Get,
+            "HEAD" => RequestMethod::Head,
+            "POST" => RequestMethod::Post,
+            "PUT" => RequestMethod::Put,
+            "DELETE" => RequestMethod::Delete,
+            "PATCH" => RequestMethod::Patch,
+            "OPTIONS" => RequestMethod::Options,
+            "TRACE" => RequestMethod::Trace,
+            "CONNECT" => RequestMethod::Connect,
+            _ => RequestMethod::Extension(method.to_string()),
+        }
+    }
 }
 
 impl Display for RequestMethod {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RequestMethod::Get => f.write_str("GET"),
+            RequestMethod::Head => f.write_str("HEAD"),
             RequestMethod::Post => f.write_str("POST"),
             RequestMethod::Put => f.write_str("PUT"),
             RequestMethod::Delete => f.write_str("DELETE"),
             RequestMethod::Patch => f.write_str("PATCH"),
             RequestMethod::Options => f.write_str("OPTIONS"),
-            RequestMethod::Unknown => f.write_str("UNKNOWN"),
+            RequestMethod::Trace => f.write_str("TRACE"),
+            RequestMethod::Connect => f.write_str("CONNECT"),
+            RequestMethod::Extension(method) => f.write_str(method),
         }
     }
 }
 
+#[derive(Clone)]
 pub struct Request {
     pub method: RequestMethod,
     pub path: String,
+    /// Protocol version from the request line, e.g. `HTTP/1.1`.
+    pub version: String,
+    pub query: QueryParams,
+    /// Header names are lowercased, a header sent more than once has its values joined
+    /// with commas.
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
+    /// Trailer fields sent after a chunked body.
+    pub trailers: HashMap<String, String>,
     pub path_params: HashMap<String, String>,
+    /// The application state, followed by whatever middleware attached to this request.
+    pub extensions: Extensions,
+}
+
+impl Request {
+    /// The value of type `T` given to [`crate::App::with_state`], or attached to this
+    /// request by a middleware through `extensions`.
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.extensions.get::<T>()
+    }
+
+    /// Case-insensitive header lookup.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// Whether the client asked for the connection to stay open after this request.
+    /// HTTP/1.1 connections are persistent unless `Connection: close` is sent, HTTP/1.0
+    /// ones only when the client opts in with `Connection: keep-alive`.
+    pub fn wants_keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            self.header("Connection").is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|option| option.trim().eq_ignore_ascii_case(token))
+            })
+        };
+        if self.version.eq_ignore_ascii_case("HTTP/1.0") {
+            has_token("keep-alive")
+        } else {
+            !has_token("close")
+        }
+    }
+
+    /// Returns the first value of the query parameter `name` parsed as `T`.
+    pub fn query_param<T: FromStr>(&self, name: &str) -> Result<T, QueryParamError> {
+        let value = self
+            .query
+            .get(name)
+            .and_then(|values| values.first())
+            .ok_or_else(|| QueryParamError::Missing(name.to_string()))?;
+        parse_query_value(name, value)
+    }
+
+    /// Returns every value of the query parameter `name` parsed as `T`.
+    /// A parameter that is not present yields an empty list.
+    pub fn query_params<T: FromStr>(&self, name: &str) -> Result<Vec<T>, QueryParamError> {
+        match self.query.get(name) {
+            Some(values) => values
+                .iter()
+                .map(|value| parse_query_value(name, value))
+                .collect(),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+/// Returned by the typed query getters on [`Request`]. Both variants describe a
+/// client error, so handlers will usually answer with a 400.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryParamError {
+    Missing(String),
+    Invalid { name: String, value: String },
+}
+
+impl Display for QueryParamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryParamError::Missing(name) => write!(f, "missing query parameter `{name}`"),
+            QueryParamError::Invalid { name, value } => {
+                write!(f, "invalid value `{value}` for query parameter `{name}`")
+            }
+        }
+    }
+}
+
+impl Error for QueryParamError {}
+
+fn parse_query_value<T: FromStr>(name: &str, value: &str) -> Result<T, QueryParamError> {
+    value.parse().map_err(|_| QueryParamError::Invalid {
+        name: name.to_string(),
+        value: value.to_string(),
+    })
+}
+
+/// Splits a raw query string (without the leading `?`) into decoded key/value pairs.
+pub(crate) fn parse_query(raw_query: &str) -> QueryParams {
+    let mut query = QueryParams::new();
+    for pair in raw_query.split('&') {
+        if pair.is_empty() {
+            continue;
+        }
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        query
+            .entry(percent_decode(&key.replace('+', " ")))
+            .or_default()
+            .push(percent_decode(&value.replace('+', " ")));
+    }
+    query
+}
+
+/// Decodes `%XX` escapes. Malformed escapes are kept as they are and invalid
+/// UTF-8 is replaced, so this never fails.
+pub(crate) fn percent_decode(input: &str) -> String {
+    String::from_utf8_lossy(&percent_decode_bytes(input)).into_owned()
+}
+
+/// Decodes `%XX` escapes into raw bytes, keeping malformed escapes as they are.
+pub(crate) fn percent_decode_bytes(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && let (Some(high), Some(low)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2]))
+        {
+            decoded.push(high << 4 | low);
+            i += 3;
+            continue;
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    decoded
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
 }
 
 impl TryFrom<&mut TcpStream> for Request {
-    type Error = Box<dyn Error + Send + Sync>;
+    type Error = ParseError;
 
     fn try_from(value: &mut TcpStream) -> Result<Self, Self::Error> {
-        let mut reader = BufReader::new(value);
-        let mut buffer_string = String::new();
+        Request::from_reader(&mut BufReader::new(value), DEFAULT_MAX_BODY_SIZE)
+    }
+}
 
-        loop {
-            let mut line = String::new();
-            let bytes_read = reader.read_line(&mut line)?;
-            if bytes_read == 0 {
-                break;
-            }
+// Longest request line accepted before answering with 414.
+const MAX_REQUEST_LINE_LENGTH: usize = 8 * 1024;
+// Largest header section accepted before answering with 431.
+const MAX_HEADERS_LENGTH: usize = 64 * 1024;
 
-            if line.eq("\r\n") {
-                break;
+/// Everything that can go wrong while reading a request off the wire.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The connection was closed or went idle before a new request started. There is
+    /// nobody left to answer, so no response is sent for this one.
+    ConnectionClosed,
+    /// The client stopped sending half way through the request.
+    Incomplete,
+    /// The client took longer than the read timeout to send the request.
+    Timeout,
+    InvalidRequestLine,
+    InvalidHeader,
+    InvalidContentLength,
+    InvalidChunkedBody,
+    UriTooLong,
+    HeadersTooLarge,
+    BodyTooLarge,
+    UnsupportedVersion(String),
+    /// A `Transfer-Encoding` other than `chunked`, the body can't be read.
+    UnsupportedTransferEncoding(String),
+    Io(io::Error),
+}
+
+impl ParseError {
+    /// The status code the client should be answered with.
+    pub fn status(&self) -> u16 {
+        match self {
+            ParseError::Timeout => 408,
+            ParseError::BodyTooLarge => 413,
+            ParseError::UriTooLong => 414,
+            ParseError::HeadersTooLarge => 431,
+            ParseError::UnsupportedTransferEncoding(_) => 501,
+            ParseError::UnsupportedVersion(_) => 505,
+            _ => 400,
+        }
+    }
+
+    fn from_io(err: io::Error, started: bool) -> Self {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut if started => ParseError::Timeout,
+            io::ErrorKind::UnexpectedEof if started => ParseError::Incomplete,
+            io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted => ParseError::ConnectionClosed,
+            _ => ParseError::Io(err),
+        }
+    }
+
+    fn from_body_io(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::FileTooLarge => ParseError::BodyTooLarge,
+            io::ErrorKind::InvalidData => ParseError::InvalidChunkedBody,
+            _ => ParseError::from_io(err, true),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::ConnectionClosed => f.write_str("connection closed"),
+            ParseError::Incomplete => f.write_str("incomplete request"),
+            ParseError::Timeout => f.write_str("timed out reading the request"),
+            ParseError::InvalidRequestLine => f.write_str("invalid request line"),
+            ParseError::InvalidHeader => f.write_str("invalid header"),
+            ParseError::InvalidContentLength => f.write_str("invalid Content-Length"),
+            ParseError::InvalidChunkedBody => f.write_str("invalid chunked body"),
+            ParseError::UriTooLong => f.write_str("request target too long"),
+            ParseError::HeadersTooLarge => f.write_str("request headers too large"),
+            ParseError::BodyTooLarge => f.write_str("request body too large"),
+            ParseError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version `{version}`")
             }
-            buffer_string.push_str(line.as_str());
+            ParseError::UnsupportedTransferEncoding(encoding) => {
+                write!(f, "unsupported transfer encoding `{encoding}`")
+            }
+            ParseError::Io(err) => write!(f, "{err}"),
         }
+    }
+}
 
-        let buffer_parts = buffer_string.split("\r\n").collect::<Vec<&str>>();
-        let mut path = String::new();
-        let mut headers = HashMap::new();
-        let mut method = String::new();
-        let mut body = vec![];
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
-        if let [first_line, headers_slice @ ..] = buffer_parts.as_slice() {
-            let first_line_parts = first_line.split(" ").collect::<Vec<&str>>();
+enum Line {
+    Complete(String),
+    TooLong,
+}
+
+// Reads a CRLF (or bare LF) terminated line of at most `limit` bytes, without the
+// line terminator.
+fn read_line<R: BufRead>(
+    reader: &mut R,
+    limit: usize,
+    started: bool,
+    on_invalid: fn() -> ParseError,
+) -> Result<Line, ParseError> {
+    let mut line = vec![];
+    reader
+        .take(limit as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .map_err(|err| ParseError::from_io(err, started || !line.is_empty()))?;
+    if line.is_empty() {
+        return Err(ParseError::from_io(
+            io::ErrorKind::UnexpectedEof.into(),
+            started,
+        ));
+    }
+    if !line.ends_with(b"\n") {
+        if line.len() > limit {
+            return Ok(Line::TooLong);
+        }
+        return Err(ParseError::Incomplete);
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Line::Complete)
+        .map_err(|_| on_invalid())
+}
 
-            // determine path and method
-            if let [raw_method, raw_path, ..] = first_line_parts.as_slice() {
-                path.insert_str(0, raw_path.trim());
-                method.insert_str(0, raw_method.trim());
+impl Request {
+    /// Reads a single request from `reader`. Bytes following the request are left
+    /// in the reader, which makes it safe to call this repeatedly on a connection.
+    pub(crate) fn from_reader<R: BufRead>(
+        reader: &mut R,
+        max_body_size: usize,
+    ) -> Result<Self, ParseError> {
+        // empty lines ahead of the request line are ignored (RFC 9112, section 2.2)
+        let request_line = loop {
+            match read_line(reader, MAX_REQUEST_LINE_LENGTH, false, || {
+                ParseError::InvalidRequestLine
+            })? {
+                Line::Complete(line) if line.is_empty() => continue,
+                Line::Complete(line) => break line,
+                Line::TooLong => return Err(ParseError::UriTooLong),
             }
+        };
 
-            // read the headers
-            for header_line in headers_slice {
-                if header_line.trim().is_empty() {
-                    continue;
+        let (method, raw_target, version) =
+            match request_line.split(' ').collect::<Vec<&str>>().as_slice() {
+                [method, target, version] if is_token(method) && target.starts_with(['/', '*']) => {
+                    (method.to_string(), target.to_string(), version.to_string())
                 }
-                if let [header_name, header_value, ..] =
-                    header_line.split(':').collect::<Vec<&str>>().as_slice()
+                _ => return Err(ParseError::InvalidRequestLine),
+            };
+        if !version.starts_with("HTTP/") {
+            return Err(ParseError::InvalidRequestLine);
+        }
+        if !version.starts_with("HTTP/1.") {
+            return Err(ParseError::UnsupportedVersion(version));
+        }
+
+        let mut headers = HashMap::new();
+        let mut headers_length = 0;
+        loop {
+            let remaining = MAX_HEADERS_LENGTH.saturating_sub(headers_length);
+            let header_line =
+                match read_line(reader, remaining, true, || ParseError::InvalidHeader)? {
+                    Line::Complete(line) => line,
+                    Line::TooLong => return Err(ParseError::HeadersTooLarge),
+                };
+            headers_length += header_line.len() + 2;
+            if header_line.is_empty() {
+                break;
+            }
+            match header_line.split_once(':') {
+                Some((header_name, header_value))
+                    if !header_name.is_empty() && header_name.trim_end() == header_name =>
                 {
-                    headers.insert(
-                        header_name.trim().to_string(),
-                        header_value.trim().to_string(),
-                    );
+                    add_header(
+                        &mut headers,
+                        header_name.to_ascii_lowercase(),
+                        header_value.trim(),
+                    )?;
                 }
+                _ => return Err(ParseError::InvalidHeader),
             }
         }
 
-        let content_length = headers.get("Content-Length");
-        if let Some(body_length) = content_length {
+        let mut body = vec![];
+        let mut trailers = HashMap::new();
+        // a proxy in front could frame the body by the other header, so rather than
+        // letting Transfer-Encoding win the request is refused (RFC 9112, section 6.3)
+        if headers.contains_key("transfer-encoding") && headers.contains_key("content-length") {
+            return Err(ParseError::InvalidContentLength);
+        }
+        if let Some(encoding) = headers.get("transfer-encoding") {
+            // other codings would have to be undone before the body could be read
+            if !encoding.eq_ignore_ascii_case("chunked") {
+                return Err(ParseError::UnsupportedTransferEncoding(encoding.clone()));
+            }
+            log::trace!("Request body is chunked");
+            let mut decoder = ChunkedDecoder::new(&mut *reader, max_body_size);
+            decoder
+                .read_to_end(&mut body)
+                .map_err(ParseError::from_body_io)?;
+            trailers = decoder.into_trailers();
+        } else if let Some(body_length) = headers.get("content-length") {
             log::trace!("Request body size={}", body_length);
-            let buff_length: usize = body_length.parse()?;
+            let buff_length =
+                parse_length(body_length, 10).ok_or(ParseError::InvalidContentLength)?;
+            if buff_length > max_body_size {
+                return Err(ParseError::BodyTooLarge);
+            }
             body = vec![0; buff_length];
-            reader.read_exact(&mut body)?;
-        }
-        Ok(Self {
-            method: match method.as_str() {
-                "GET" => RequestMethod::Get,
-                "POST" => RequestMethod::Post,
-                "PUT" => RequestMethod::Put,
-                "DELETE" => RequestMethod::Delete,
-                "PATH" => RequestMethod::Patch,
-                "OPTIONS" => RequestMethod::Options,
-                _ => RequestMethod::Unknown,
-            },
+            reader
+                .read_exact(&mut body)
+                .map_err(ParseError::from_body_io)?;
+        }
+        Ok(Self::from_parts(
+            &method, raw_target, version, headers, body, trailers,
+        ))
+    }
+
+    /// Builds a request from parts that were already read off the wire, splitting the
+    /// query string from `target`.
+    pub(crate) fn from_parts(
+        method: &str,
+        target: String,
+        version: String,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+        trailers: HashMap<String, String>,
+    ) -> Self {
+        let (path, query) = match target.split_once('?') {
+            Some((raw_path, raw_query)) => (raw_path.to_string(), parse_query(raw_query)),
+            None => (target, QueryParams::new()),
+        };
+        Self {
+            method: RequestMethod::from(method),
             path,
+            version,
+            query,
             headers,
-            body: body.to_vec(),
+            body,
+            trailers,
             path_params: HashMap::new(),
-        })
+            extensions: Extensions::new(),
+        }
+    }
+}
+
+/// Whether `value` is a valid token (RFC 9110, section 5.6.2), the syntax of method
+/// and header names.
+pub(crate) fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+// Adds a header read off the wire. Repeated headers are joined into a list, except for
+// the ones framing the body, which a proxy in front might pick differently.
+fn add_header(
+    headers: &mut HashMap<String, String>,
+    name: String,
+    value: &str,
+) -> Result<(), ParseError> {
+    match headers.get_mut(&name) {
+        Some(_) if name == "transfer-encoding" => Err(ParseError::InvalidHeader),
+        // the same length repeated is harmless, differing ones are refused (RFC 9112, section 6.3)
+        Some(existing) if name == "content-length" && existing == value => Ok(()),
+        Some(_) if name == "content-length" => Err(ParseError::InvalidContentLength),
+        Some(existing) => {
+            let separator = if name == "cookie" { "; " } else { ", " };
+            existing.push_str(separator);
+            existing.push_str(value);
+            Ok(())
+        }
+        None => {
+            headers.insert(name, value.to_string());
+            Ok(())
+        }
+    }
+}
+
+fn find_header<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ParseError, QueryParamError, QueryParams, RequestMethod, is_token, parse_query,
+        percent_decode,
+    };
+
+    #[test]
+    fn parses_standard_and_extension_methods() {
+        assert!(RequestMethod::from("PATCH") == RequestMethod::Patch);
+        assert!(RequestMethod::from("CONNECT") == RequestMethod::Connect);
+        assert!(RequestMethod::from("PROPFIND") == RequestMethod::Extension("PROPFIND".into()));
+        // method names are case sensitive
+        assert!(RequestMethod::from("get") == RequestMethod::Extension("get".into()));
+        assert_eq!(RequestMethod::from("MKCOL").to_string(), "MKCOL");
+        assert_eq!(RequestMethod::Trace.to_string(), "TRACE");
+        assert!(is_token("M-SEARCH"));
+        assert!(!is_token("GET{}") && !is_token(""));
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("hello%20world"), "hello world");
+        assert_eq!(percent_decode("%E2%9C%93"), "\u{2713}");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn parses_query_strings() {
+        let query = parse_query("q=rust+lang&tag=a&tag=b&flag&&empty=");
+        assert_eq!(query.get("q"), Some(&vec!["rust lang".to_string()]));
+        assert_eq!(
+            query.get("tag"),
+            Some(&vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(query.get("flag"), Some(&vec![String::new()]));
+        assert_eq!(query.get("empty"), Some(&vec![String::new()]));
+        assert_eq!(query.len(), 4);
+        assert_eq!(parse_query(""), QueryParams::new());
+    }
+
+    #[test]
+    fn typed_query_getters() {
+        let mut request = super::Request {
+            method: super::RequestMethod::Get,
+            path: "/search".into(),
+            version: "HTTP/1.1".into(),
+            query: parse_query("page=2&id=1&id=x&ids=3&ids=4"),
+            headers: Default::default(),
+            body: vec![],
+            trailers: Default::default(),
+            path_params: Default::default(),
+            extensions: Default::default(),
+        };
+        assert_eq!(request.query_param::<u32>("page"), Ok(2));
+        assert_eq!(
+            request.query_param::<u32>("limit"),
+            Err(QueryParamError::Missing("limit".into()))
+        );
+        assert_eq!(request.query_params::<u32>("ids"), Ok(vec![3, 4]));
+        assert_eq!(
+            request.query_params::<u32>("id"),
+            Err(QueryParamError::Invalid {
+                name: "id".into(),
+                value: "x".into()
+            })
+        );
+        request.query.clear();
+        assert_eq!(request.query_params::<u32>("ids"), Ok(vec![]));
+    }
+
+    #[test]
+    fn reads_chunked_bodies() {
+        let raw = "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   5\r\nhello\r\n6\r\n world\r\n0\r\nX-Sum: 11\r\n\r\n";
+        let request = super::Request::from_reader(&mut raw.as_bytes(), 1024).unwrap();
+        assert_eq!(request.body, b"hello world");
+        assert_eq!(request.trailers.get("X-Sum").unwrap(), "11");
+
+        let too_large = super::Request::from_reader(&mut raw.as_bytes(), 8);
+        assert!(matches!(too_large, Err(ParseError::BodyTooLarge)));
+
+        let both = "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n\
+                    5\r\nhello\r\n0\r\n\r\n";
+        let both = super::Request::from_reader(&mut both.as_bytes(), 1024);
+        assert!(matches!(both, Err(ParseError::InvalidContentLength)));
+
+        let mixed_case = "POST /upload HTTP/1.1\r\ntransfer-encoding: chunked\r\n\
+                          Content-Length: 5\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        let mixed_case = super::Request::from_reader(&mut mixed_case.as_bytes(), 1024);
+        assert!(matches!(mixed_case, Err(ParseError::InvalidContentLength)));
+    }
+
+    #[test]
+    fn normalizes_header_names() {
+        let raw = b"GET / HTTP/1.1\r\nAccept: text/html\r\naccept: text/plain\r\n\
+                    Cookie: a=1\r\nCOOKIE: b=2\r\n\r\n";
+        let request = super::Request::from_reader(&mut &raw[..], 1024).unwrap();
+        assert_eq!(request.headers.len(), 2);
+        assert_eq!(request.header("Accept"), Some("text/html, text/plain"));
+        assert_eq!(request.headers.get("cookie").unwrap(), "a=1; b=2");
+    }
+
+    #[test]
+    fn refuses_unreadable_framing() {
+        let status = |raw: &[u8]| {
+            super::Request::from_reader(&mut &raw[..], 1024)
+                .err()
+                .map(|err| err.status())
+        };
+
+        assert_eq!(
+            status(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+                  transfer-encoding: chunked\r\n\r\n0\r\n\r\n"
+            ),
+            Some(400)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"),
+            Some(501)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n"),
+            Some(501)
+        );
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        let parse = |raw: &[u8]| super::Request::from_reader(&mut &raw[..], 1024).err();
+        let status = |raw: &[u8]| parse(raw).map(|err| err.status());
+
+        assert!(matches!(parse(b""), Some(ParseError::ConnectionClosed)));
+        assert!(matches!(
+            parse(b"GET / HTTP/1.1\r\nHost: loc"),
+            Some(ParseError::Incomplete)
+        ));
+        assert_eq!(status(b"GET\r\n\r\n"), Some(400));
+        assert_eq!(status(b"GET / HTTP/1.1\r\nNo colon\r\n\r\n"), Some(400));
+        assert_eq!(status(b"GET / HTTP/1.1\r\nBad\xff: x\r\n\r\n"), Some(400));
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n"),
+            Some(400)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nContent-Length: +2\r\n\r\nhi"),
+            Some(400)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nContent-Length: 2048\r\n\r\n"),
+            Some(413)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 5\r\n\r\nhi"),
+            Some(400)
+        );
+        assert_eq!(status(b"GET / HTTP/2.0\r\n\r\n"), Some(505));
+
+        let long_target = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(9000));
+        assert_eq!(status(long_target.as_bytes()), Some(414));
+        let long_header = format!("GET / HTTP/1.1\r\nX-Big: {}\r\n\r\n", "a".repeat(70000));
+        assert_eq!(status(long_header.as_bytes()), Some(431));
+    }
+
+    #[test]
+    fn accepts_repeated_identical_content_lengths() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nhi";
+        let request = super::Request::from_reader(&mut &raw[..], 1024).unwrap();
+        assert_eq!(request.header("Content-Length"), Some("2"));
+        assert_eq!(request.body, b"hi");
+    }
+
+    #[test]
+    fn keeps_colons_in_header_values() {
+        let raw = b"GET / HTTP/1.1\r\nHost: localhost:8080\r\n\r\n";
+        let request = super::Request::from_reader(&mut &raw[..], 1024).unwrap();
+        assert_eq!(request.header("host"), Some("localhost:8080"));
     }
 }
@@ -1,19 +1,38 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use crate::{
+    body::{Body, Framing, Trailers},
+    connection::{Upgrade, Upgraded},
+    request::Request,
+    sse::{DEFAULT_HEARTBEAT, EventSender, EventStream},
+};
 
 #[derive(Default)]
 pub struct Response {
     headers: HashMap<String, String>,
-    body: Vec<u8>,
+    body: Body,
+    trailers: Option<Trailers>,
+    upgrade: Option<Upgrade>,
     status_code: u16,
 }
 
+// Returned by `get_body` for bodies that are not held in memory.
+static EMPTY_BODY: Vec<u8> = Vec::new();
+
 impl Response {
     pub fn new() -> Self {
         let mut headers = HashMap::new();
         headers.insert("Content-Type".into(), "text/plain".into());
         Self {
             headers,
-            body: vec![],
+            body: Body::Empty,
+            trailers: None,
+            upgrade: None,
             status_code: 0,
         }
     }
@@ -24,22 +43,177 @@ impl Response {
         self
     }
 
-    pub fn set_body(mut self, body: &str) -> Self {
-        self.body = body.as_bytes().to_vec();
+    pub(crate) fn remove_header(mut self, header_name: &str) -> Self {
         self.headers
-            .insert("Content-Length".to_owned(), self.body.len().to_string());
+            .retain(|name, _| !name.eq_ignore_ascii_case(header_name));
         self
     }
 
-    pub fn set_body_bytes(mut self, body: &[u8]) -> Self {
-        self.body = body.to_vec();
+    pub fn set_body(self, body: &str) -> Self {
+        self.set_body_from(body)
+    }
+
+    pub fn set_body_bytes(self, body: &[u8]) -> Self {
+        self.set_body_from(body)
+    }
+
+    /// Replaces the body, keeping `Content-Length` in sync with it.
+    pub fn set_body_from(mut self, body: impl Into<Body>) -> Self {
+        self.body = body.into();
         self.headers
-            .insert("Content-Length".to_string(), self.body.len().to_string());
+            .retain(|name, _| !name.eq_ignore_ascii_case("Content-Length"));
+        if let Some(length) = self.body.len() {
+            self.headers
+                .insert("Content-Length".to_string(), length.to_string());
+        }
+        self
+    }
+
+    /// Streams `length` bytes from `reader` when the response is sent instead of
+    /// holding them in memory.
+    pub fn set_body_reader<R>(self, reader: R, length: u64) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        self.set_body_from(Body::from_reader_with_length(reader, length))
+    }
+
+    /// Streams everything `reader` produces. The length is not known up front, so the
+    /// body is sent with `Transfer-Encoding: chunked`.
+    pub fn set_body_stream<R>(self, reader: R) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        self.set_body_from(Body::from_reader(reader))
+    }
+
+    /// Sends every chunk produced by `chunks` as soon as it is available.
+    pub fn set_body_chunks<I>(self, chunks: I) -> Self
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        self.set_body_from(Body::from_chunks(chunks))
+    }
+
+    /// Streams `file` from its current position.
+    pub fn set_body_file(self, file: File) -> Self {
+        self.set_body_from(Body::from_file(file))
+    }
+
+    /// Turns the response into a Server-Sent Events stream. `producer` runs on its own
+    /// thread once the response is sent and pushes events until it returns or the client
+    /// disconnects. A heartbeat comment goes out every [`DEFAULT_HEARTBEAT`] while no
+    /// event is sent.
+    pub fn sse<F>(self, request: &Request, producer: F) -> Self
+    where
+        F: FnOnce(EventSender) + Send + 'static,
+    {
+        self.sse_with_heartbeat(request, DEFAULT_HEARTBEAT, producer)
+    }
+
+    /// Same as [`Response::sse`] with a custom heartbeat interval.
+    pub fn sse_with_heartbeat<F>(
+        mut self,
+        request: &Request,
+        heartbeat: Duration,
+        producer: F,
+    ) -> Self
+    where
+        F: FnOnce(EventSender) + Send + 'static,
+    {
+        if self.status_code == 0 {
+            self.status_code = 200;
+        }
+        let last_event_id = request.header("Last-Event-ID").map(str::to_string);
+        let stream = EventStream::new(Box::new(producer), last_event_id, heartbeat);
+        self.add_header("Content-Type", "text/event-stream")
+            .add_header("Cache-Control", "no-cache")
+            .set_body_from(Body::Chunks(Box::new(stream)))
+    }
+
+    /// Hands the connection to `upgrade` once this response has been sent.
+    pub(crate) fn set_upgrade<F>(mut self, upgrade: F) -> Self
+    where
+        F: FnOnce(Upgraded) + Send + 'static,
+    {
+        self.upgrade = Some(Box::new(upgrade));
+        self
+    }
+
+    pub(crate) fn take_upgrade(&mut self) -> Option<Upgrade> {
+        self.upgrade.take()
+    }
+
+    /// Splits the response up for protocols that frame it differently than HTTP/1.1.
+    pub(crate) fn into_parts(self) -> (u16, HashMap<String, String>, Body, Option<Trailers>) {
+        (self.status_code, self.headers, self.body, self.trailers)
+    }
+
+    pub(crate) fn is_event_stream(&self) -> bool {
+        self.get_header("Content-Type")
+            .is_some_and(|content_type| content_type.starts_with("text/event-stream"))
+    }
+
+    /// Trailer fields sent after a chunked body. `trailers` runs once the whole body has
+    /// been written, so it can report values computed while streaming, like a checksum.
+    /// Trailers are dropped when the body is not sent chunked.
+    pub fn set_trailers<F>(mut self, trailers: F) -> Self
+    where
+        F: FnOnce() -> Vec<(String, String)> + Send + 'static,
+    {
+        self.trailers = Some(Box::new(trailers));
         self
     }
 
+    /// Case-insensitive header lookup.
+    pub fn get_header(&self, header_name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(header_name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the body when it is held in memory. Streamed bodies are only read while
+    /// the response is being sent, so an empty body is returned for those.
     pub fn get_body(&self) -> &Vec<u8> {
-        &self.body
+        match &self.body {
+            Body::Bytes(bytes) => bytes,
+            _ => &EMPTY_BODY,
+        }
+    }
+
+    /// Length of the body in bytes, `None` for streams of unknown length.
+    pub(crate) fn body_length(&self) -> Option<u64> {
+        self.body.len()
+    }
+
+    pub(crate) fn is_streamed(&self) -> bool {
+        self.body.is_streamed()
+    }
+
+    /// Whether the end of the body can only be signalled by closing the connection.
+    pub(crate) fn is_close_delimited(&self, chunked_allowed: bool) -> bool {
+        self.framing(chunked_allowed) == Framing::CloseDelimited
+    }
+
+    /// 1xx, 204 and 304 responses end with their headers, whatever body was set on them
+    /// (RFC 9112, section 6.3).
+    fn forbids_body(&self) -> bool {
+        matches!(self.status_code, 100..=199 | 204 | 304)
+    }
+
+    fn framing(&self, chunked_allowed: bool) -> Framing {
+        let wants_chunked = self.body.len().is_none() || self.trailers.is_some();
+        if self.forbids_body() || !wants_chunked {
+            Framing::Length
+        } else if chunked_allowed {
+            Framing::Chunked
+        } else if self.body.len().is_some() {
+            Framing::Length
+        } else {
+            Framing::CloseDelimited
+        }
     }
 
     pub fn set_status(mut self, status_code: u16) -> Self {
@@ -51,15 +225,38 @@ impl Response {
         self.status_code
     }
 
-    pub fn build(self) -> Vec<u8> {
-        let status_string = match self.status_code {
-            200 => "200 OK",
-            201 => "201 Created",
-            401 => "401 Access Denied",
-            404 => "404 Not Found",
-            500 => "500 Internal Server Error",
-            _ => "500 Internal Server Error",
+    fn head(&mut self, framing: Framing) -> String {
+        let status_string = match reason_phrase(self.status_code) {
+            Some(reason) => format!("{} {}", self.status_code, reason),
+            None => "500 Internal Server Error".to_string(),
         };
+        match framing {
+            // persistent connections rely on the length to find where the next response
+            // starts, responses that never carry a body are the exception
+            Framing::Length if !self.forbids_body() => {
+                let length = self.body.len().unwrap_or_default();
+                self.headers
+                    .entry("Content-Length".to_string())
+                    .or_insert_with(|| length.to_string());
+            }
+            // a 304 may give the length of the representation it stands for, the other
+            // statuses without a body must not send one (RFC 9110, section 8.6)
+            Framing::Length if self.status_code != 304 => {
+                self.headers
+                    .retain(|name, _| !name.eq_ignore_ascii_case("Content-Length"));
+            }
+            Framing::Length => {}
+            Framing::Chunked => {
+                self.headers
+                    .retain(|name, _| !name.eq_ignore_ascii_case("Content-Length"));
+                self.headers
+                    .insert("Transfer-Encoding".to_string(), "chunked".to_string());
+            }
+            Framing::CloseDelimited => {
+                self.headers
+                    .retain(|name, _| !name.eq_ignore_ascii_case("Content-Length"));
+            }
+        }
         let headers = self
             .headers
             .iter()
@@ -67,10 +264,187 @@ impl Response {
             .collect::<Vec<String>>()
             .join("\r\n");
 
-        [
-            format!("HTTP/1.1 {}\r\n{}\r\n\r\n", status_string, headers).as_bytes(),
-            self.body.as_slice(),
-        ]
-        .concat()
+        format!("HTTP/1.1 {}\r\n{}\r\n\r\n", status_string, headers)
+    }
+
+    /// Serializes the whole response, reading streamed bodies into memory.
+    pub fn build(self) -> Vec<u8> {
+        let mut buffer = vec![];
+        let _ = self.write_to(&mut buffer, true);
+        buffer
+    }
+
+    /// Writes only the status line and headers, answering a `HEAD` request. They describe
+    /// the body a `GET` would have carried, `Content-Length` included.
+    pub(crate) fn write_head_to<W: Write + ?Sized>(
+        mut self,
+        writer: &mut W,
+        chunked_allowed: bool,
+    ) -> io::Result<()> {
+        let framing = self.framing(chunked_allowed);
+        writer.write_all(self.head(framing).as_bytes())?;
+        writer.flush()
+    }
+
+    /// Writes the response to `writer`, streaming the body in fixed-size chunks.
+    /// Bodies of unknown length are sent chunked when `chunked_allowed`, otherwise
+    /// the connection has to be closed after the body. Statuses that never carry a
+    /// body are written like the answer to a `HEAD` request.
+    pub(crate) fn write_to<W: Write + ?Sized>(
+        mut self,
+        writer: &mut W,
+        chunked_allowed: bool,
+    ) -> io::Result<()> {
+        if self.forbids_body() {
+            return self.write_head_to(writer, chunked_allowed);
+        }
+        let framing = self.framing(chunked_allowed);
+        writer.write_all(self.head(framing).as_bytes())?;
+        let trailers = self.trailers.take();
+        std::mem::take(&mut self.body).write_to(writer, framing, trailers)?;
+        writer.flush()
+    }
+}
+
+/// Anything a route handler can return. Plain values become a `200 OK` response, pair
+/// them with a status code as in `(201, "created")` to pick another one.
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl IntoResponse for &str {
+    fn into_response(self) -> Response {
+        Response::new().set_body(self).set_status(200)
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        self.as_str().into_response()
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Response {
+        Response::new()
+            .add_header("Content-Type", "application/octet-stream")
+            .set_body_from(self)
+            .set_status(200)
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (u16, T) {
+    fn into_response(self) -> Response {
+        let (status_code, response) = self;
+        response.into_response().set_status(status_code)
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(response) => response.into_response(),
+            Err(err) => err.into_response(),
+        }
+    }
+}
+
+/// `None` is answered with `404 Not Found`.
+impl<T: IntoResponse> IntoResponse for Option<T> {
+    fn into_response(self) -> Response {
+        match self {
+            Some(response) => response.into_response(),
+            None => Response::new().set_status(404),
+        }
+    }
+}
+
+fn reason_phrase(status_code: u16) -> Option<&'static str> {
+    let reason = match status_code {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Access Denied",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        422 => "Unprocessable Content",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => return None,
+    };
+    Some(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IntoResponse, Response};
+
+    #[test]
+    fn converts_plain_values() {
+        let response = "hello".into_response();
+        assert_eq!(response.get_status(), 200);
+        assert_eq!(response.get_body(), b"hello");
+        assert_eq!(response.get_header("Content-Type"), Some("text/plain"));
+
+        let response = vec![1, 2, 3].into_response();
+        assert_eq!(response.get_body(), &[1, 2, 3]);
+        assert_eq!(response.get_header("Content-Length"), Some("3"));
+        assert_eq!(
+            response.get_header("Content-Type"),
+            Some("application/octet-stream")
+        );
+
+        let response = Response::new().set_status(204).into_response();
+        assert_eq!(response.get_status(), 204);
+    }
+
+    #[test]
+    fn converts_status_pairs_results_and_options() {
+        let response = (201, String::from("created")).into_response();
+        assert_eq!(response.get_status(), 201);
+        assert_eq!(response.get_body(), b"created");
+
+        let ok: Result<&str, (u16, &str)> = Ok("found");
+        assert_eq!(ok.into_response().get_status(), 200);
+        let err: Result<&str, (u16, &str)> = Err((400, "bad input"));
+        let response = err.into_response();
+        assert_eq!(response.get_status(), 400);
+        assert_eq!(response.get_body(), b"bad input");
+
+        assert_eq!(Some("here").into_response().get_status(), 200);
+        assert_eq!(None::<&str>.into_response().get_status(), 404);
     }
 }
@@ -1,4 +1,7 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+};
 
 pub type PathParams = HashMap<String, String>;
 
@@ -17,6 +20,9 @@ pub struct Node {
     pub(crate) url: Option<String>,
     // A wildcard node is a node that accepts any url segments below itself.
     pub(crate) is_wildcard: bool,
+    // Set on the root only. Unless it is set, literal segments match regardless of case
+    // and are keyed in lowercase. Path param values always keep the case they were sent in.
+    pub(crate) case_sensitive: bool,
 }
 
 impl Node {
@@ -29,11 +35,20 @@ impl Node {
             is_terminal: false,
             is_wildcard: false,
             children: HashMap::new(),
+            case_sensitive: false,
+        }
+    }
+
+    /// A root node whose literal segments only match urls using the same case.
+    pub fn new_case_sensitive() -> Self {
+        Self {
+            case_sensitive: true,
+            ..Self::new()
         }
     }
 
     pub fn append(&mut self, url_path_pattern: &str) {
-        let url_path_pattern = url_path_pattern.trim().to_lowercase();
+        let url_path_pattern = url_path_pattern.trim();
         let mut url_parts = url_path_pattern.split('/').collect::<VecDeque<&str>>();
         if let Some(first) = url_parts.front() {
             if first.is_empty() {
@@ -43,22 +58,19 @@ impl Node {
             return;
         }
 
+        let case_sensitive = self.case_sensitive;
         let mut node_ref = self;
         while let Some(segment) = url_parts.pop_front() {
             let segment = segment.trim();
             let is_terminal = url_parts.is_empty();
-            let is_wildcard = if !is_terminal && url_parts.iter().next().unwrap().eq(&"*") {
-                true
-            } else {
-                false
-            };
+            let is_wildcard = !is_terminal && url_parts.iter().next().unwrap().eq(&"*");
             node_ref = node_ref
                 .children
-                .entry(segment.to_string())
+                .entry(key(segment, case_sensitive).into_owned())
                 .or_insert(Node {
                     value: segment.to_owned(),
                     url: if is_terminal || is_wildcard {
-                        Some(url_path_pattern.clone())
+                        Some(url_path_pattern.to_string())
                     } else {
                         None
                     },
@@ -66,6 +78,7 @@ impl Node {
                     is_terminal,
                     is_wildcard,
                     children: HashMap::new(),
+                    case_sensitive: false,
                 });
             if is_wildcard {
                 break;
@@ -80,7 +93,7 @@ impl Node {
     }
 
     pub fn find_match(&self, url_string: &str) -> Option<(String, PathParams)> {
-        let url_string = url_string.trim().to_lowercase();
+        let url_string = url_string.trim();
         if self.children.is_empty() && !url_string.is_empty() {
             return None;
         }
@@ -102,7 +115,11 @@ impl Node {
         let mut node_ref = self;
         let mut path_params: PathParams = PathParams::new();
         while let Some(segment) = url_parts.pop_front() {
-            if let Some(node) = node_ref.children.get(segment.trim()) {
+            if let Some(node) = node_ref
+                .children
+                .get(key(segment.trim(), self.case_sensitive).as_ref())
+                .filter(|node| !node.is_param)
+            {
                 if node.is_terminal && url_parts.is_empty() {
                     return Some((node.url.clone().unwrap(), path_params));
                 }
@@ -124,6 +141,26 @@ impl Node {
     }
 }
 
+// The children key of a url segment. Param names are kept as written.
+/// The form `url_pattern` is stored in, two patterns with the same key answer the same
+/// urls.
+pub(crate) fn pattern_key(url_pattern: &str, case_sensitive: bool) -> String {
+    url_pattern
+        .trim()
+        .split('/')
+        .map(|segment| key(segment, case_sensitive))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn key(segment: &str, case_sensitive: bool) -> Cow<'_, str> {
+    if case_sensitive || segment.starts_with(':') {
+        Cow::Borrowed(segment)
+    } else {
+        Cow::Owned(segment.to_lowercase())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::route_matcher::PathParams;
@@ -308,4 +345,49 @@ mod tests {
         let (pattern, _) = find_result.unwrap();
         assert_eq!(pattern, "/static/*");
     }
+
+    #[test]
+    fn keeps_the_case_of_path_param_values_and_names() {
+        let mut node = Node::new();
+        node.append("/users/:userName/files/:fileName");
+        let (pattern, params) = node.find_match("/Users/Alice/FILES/Report.PDF").unwrap();
+        assert_eq!(pattern, "/users/:userName/files/:fileName");
+        assert_eq!(
+            params,
+            PathParams::from([
+                ("userName".into(), "Alice".into()),
+                ("fileName".into(), "Report.PDF".into())
+            ])
+        );
+    }
+
+    #[test]
+    fn matches_literal_segments_case_sensitively_when_asked() {
+        let mut node = Node::new_case_sensitive();
+        node.append("/Docs/:token");
+        node.append("/docs/latest");
+        let (pattern, params) = node.find_match("/Docs/aGVsbG8=").unwrap();
+        assert_eq!(pattern, "/Docs/:token");
+        assert_eq!(
+            params,
+            PathParams::from([("token".into(), "aGVsbG8=".into())])
+        );
+        assert_eq!(
+            node.find_match("/docs/latest"),
+            Some(("/docs/latest".into(), PathParams::new()))
+        );
+        assert_eq!(node.find_match("/DOCS/latest"), None);
+        assert_eq!(node.find_match("/docs/Latest"), None);
+    }
+
+    #[test]
+    fn param_segments_in_urls_are_not_taken_for_param_names() {
+        let mut node = Node::new();
+        node.append("/echo/:param");
+        let (_, params) = node.find_match("/echo/:param").unwrap();
+        assert_eq!(
+            params,
+            PathParams::from([("param".into(), ":param".into())])
+        );
+    }
 }
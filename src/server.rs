@@ -0,0 +1,360 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::Result;
+
+struct TrackedConnection {
+    // a clone of the accepted socket, used to close it from the shutting down thread
+    stream: TcpStream,
+    // idle connections are waiting for the next request and can be closed right away
+    idle: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct ServerState {
+    shutting_down: AtomicBool,
+    local_addr: Mutex<Option<SocketAddr>>,
+    next_connection_id: AtomicUsize,
+    connections: Mutex<HashMap<usize, TrackedConnection>>,
+    // notified every time a connection is dropped or the shutdown deadline changes
+    connection_closed: Condvar,
+    // after this instant shutting down stops waiting for connections to finish
+    deadline: Mutex<Option<Instant>>,
+    abandoned: AtomicBool,
+    // connections served on threads of their own instead of a worker
+    detached_connections: AtomicUsize,
+    #[cfg(feature = "tls")]
+    tls: Mutex<Option<Arc<crate::tls::TlsAcceptor>>>,
+}
+
+/// Controls a running [`crate::App`]. Handles are cheap to clone and can be sent to
+/// other threads, e.g. a test harness or a signal watcher.
+#[derive(Clone, Default)]
+pub struct ServerHandle {
+    pub(crate) state: Arc<ServerState>,
+}
+
+impl ServerHandle {
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting connections and waits for every in-flight request to finish.
+    /// Idle keep-alive connections are closed straight away.
+    pub fn shutdown(&self) {
+        self.begin_shutdown();
+        if !self.state.wait_for_connections() {
+            self.state.abandon_connections();
+        }
+    }
+
+    /// Same as [`ServerHandle::shutdown`] but gives up waiting after `timeout` and
+    /// closes whatever connections are still open. Returns `true` if every connection
+    /// finished on its own.
+    ///
+    /// Handlers still running at that point, e.g. busy computing or blocked on
+    /// something other than their connection, are abandoned: the server stops without
+    /// them and their worker threads are left to finish, or to die with the process.
+    pub fn shutdown_with_timeout(&self, timeout: Duration) -> bool {
+        self.begin_shutdown();
+        self.state.set_deadline(Instant::now() + timeout);
+        if self.state.wait_for_connections() {
+            return true;
+        }
+        self.state.abandon_connections();
+        false
+    }
+
+    /// Shuts the server down once the process receives SIGINT or SIGTERM, allowing
+    /// in-flight requests up to `timeout` to finish. See
+    /// [`ServerHandle::shutdown_with_timeout`] for what happens to the ones that don't.
+    #[cfg(unix)]
+    pub fn shutdown_on_signal(&self, timeout: Duration) {
+        let handle = self.clone();
+        signal::watch(move || {
+            log::info!("Received termination signal, shutting down");
+            handle.shutdown_with_timeout(timeout);
+        });
+    }
+
+    /// Reads the TLS certificates and keys again, e.g. after they were renewed.
+    /// Connections accepted from then on use the new certificates. If any file fails to
+    /// load the error is returned and the current certificates stay in use.
+    #[cfg(feature = "tls")]
+    pub fn reload_tls(&self) -> Result<()> {
+        match self.state.tls_acceptor() {
+            Some(acceptor) => {
+                acceptor.reload()?;
+                log::info!("Reloaded TLS certificates");
+                Ok(())
+            }
+            None => Err("TLS is not enabled on this server".into()),
+        }
+    }
+
+    fn begin_shutdown(&self) {
+        if self.state.shutting_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // the accept loop is blocked waiting for a connection, give it one so it
+        // notices the server is shutting down
+        if let Some(addr) = *self.state.local_addr.lock().unwrap() {
+            let _ = TcpStream::connect_timeout(&loopback(addr), Duration::from_secs(1));
+        }
+        for connection in self.state.connections.lock().unwrap().values() {
+            if connection.idle {
+                let _ = connection.stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+/// A server started with [`crate::App::spawn`], accepting connections on a background
+/// thread.
+pub struct Server {
+    local_addr: SocketAddr,
+    handle: ServerHandle,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl Server {
+    pub(crate) fn new(
+        local_addr: SocketAddr,
+        handle: ServerHandle,
+        thread: JoinHandle<Result<()>>,
+    ) -> Self {
+        Self {
+            local_addr,
+            handle,
+            thread,
+        }
+    }
+
+    /// The address the listener is bound to, including the port picked by the OS.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Shuts the server down, see [`ServerHandle::shutdown`], and waits for the
+    /// background thread to exit.
+    pub fn shutdown(self) -> Result<()> {
+        self.handle.shutdown();
+        self.join()
+    }
+
+    /// Shuts the server down, see [`ServerHandle::shutdown_with_timeout`], and waits
+    /// for the background thread to exit.
+    pub fn shutdown_with_timeout(self, timeout: Duration) -> Result<bool> {
+        let drained = self.handle.shutdown_with_timeout(timeout);
+        self.join()?;
+        Ok(drained)
+    }
+
+    /// Blocks until the server stops, e.g. after a shutdown triggered from a handle.
+    pub fn join(self) -> Result<()> {
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err("the accept loop panicked".into()))
+    }
+}
+
+impl ServerState {
+    pub fn set_local_addr(&self, addr: SocketAddr) {
+        *self.local_addr.lock().unwrap() = Some(addr);
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn set_tls_acceptor(&self, acceptor: crate::tls::TlsAcceptor) {
+        *self.tls.lock().unwrap() = Some(Arc::new(acceptor));
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls_acceptor(&self) -> Option<Arc<crate::tls::TlsAcceptor>> {
+        self.tls.lock().unwrap().clone()
+    }
+
+    /// Starts tracking an accepted connection until the returned guard is dropped.
+    pub fn track(self: &Arc<Self>, stream: &TcpStream) -> std::io::Result<ConnectionGuard> {
+        let id = self.next_connection_id.fetch_add(1, Ordering::SeqCst);
+        let stream = stream.try_clone()?;
+        // the client may have sent its request already, it's only idle once a worker
+        // has answered it
+        self.connections.lock().unwrap().insert(
+            id,
+            TrackedConnection {
+                stream,
+                idle: false,
+            },
+        );
+        Ok(ConnectionGuard {
+            state: self.clone(),
+            id,
+            detached: false,
+        })
+    }
+
+    /// Moves the shutdown deadline to `deadline` unless an earlier one was set.
+    fn set_deadline(&self, deadline: Instant) {
+        {
+            let mut current = self.deadline.lock().unwrap();
+            *current = Some(current.map_or(deadline, |current| current.min(deadline)));
+        }
+        // waiters read the deadline while holding the connections lock, taking it here
+        // makes sure they are either waiting already or will see the new one
+        drop(self.connections.lock().unwrap());
+        self.connection_closed.notify_all();
+    }
+
+    /// Waits until no connection is left, returning `false` if the shutdown deadline
+    /// passed first.
+    pub fn wait_for_connections(&self) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        while !connections.is_empty() {
+            let deadline = *self.deadline.lock().unwrap();
+            connections = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.connection_closed
+                        .wait_timeout(connections, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.connection_closed.wait(connections).unwrap(),
+            };
+        }
+        true
+    }
+
+    /// Closes the connections still open after the shutdown deadline. Their handlers
+    /// are not waited for any longer.
+    pub fn abandon_connections(&self) {
+        if self.abandoned.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let connections = self.connections.lock().unwrap();
+        log::warn!(
+            "Shutdown timed out, abandoning {} connection(s)",
+            connections.len()
+        );
+        for connection in connections.values() {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Keeps a connection registered with the server while it is being served.
+pub(crate) struct ConnectionGuard {
+    state: Arc<ServerState>,
+    id: usize,
+    detached: bool,
+}
+
+impl ConnectionGuard {
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn set_idle(&self, idle: bool) {
+        if let Some(connection) = self.state.connections.lock().unwrap().get_mut(&self.id) {
+            connection.idle = idle;
+        }
+    }
+
+    /// Counts the connection as served outside the worker pool, unless `limit` such
+    /// connections are open already. Returns `false` if the connection was detached
+    /// before or the limit is reached.
+    pub fn detach(&mut self, limit: usize) -> bool {
+        if self.detached {
+            return false;
+        }
+        self.detached = self
+            .state
+            .detached_connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < limit).then_some(count + 1)
+            })
+            .is_ok();
+        self.detached
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.detached {
+            self.state
+                .detached_connections
+                .fetch_sub(1, Ordering::SeqCst);
+        }
+        self.state.connections.lock().unwrap().remove(&self.id);
+        self.state.connection_closed.notify_all();
+    }
+}
+
+// Servers bound to every interface can't be connected to on the unspecified address.
+fn loopback(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if v4.ip().is_unspecified() => {
+            SocketAddr::from((Ipv4Addr::LOCALHOST, v4.port()))
+        }
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => {
+            SocketAddr::from((Ipv6Addr::LOCALHOST, v6.port()))
+        }
+        _ => addr,
+    }
+}
+
+#[cfg(unix)]
+mod signal {
+    use std::{
+        os::raw::c_int,
+        sync::{
+            Once,
+            atomic::{AtomicBool, Ordering},
+        },
+        thread,
+        time::Duration,
+    };
+
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+
+    static RECEIVED: AtomicBool = AtomicBool::new(false);
+    static INSTALL: Once = Once::new();
+
+    unsafe extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    extern "C" fn on_signal(_: c_int) {
+        // only async-signal-safe work is allowed here, the watcher thread does the rest
+        RECEIVED.store(true, Ordering::SeqCst);
+    }
+
+    pub fn watch<F: FnOnce() + Send + 'static>(on_received: F) {
+        INSTALL.call_once(|| unsafe {
+            signal(SIGINT, on_signal as extern "C" fn(c_int) as usize);
+            signal(SIGTERM, on_signal as extern "C" fn(c_int) as usize);
+        });
+        thread::spawn(move || {
+            while !RECEIVED.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(100));
+            }
+            on_received();
+        });
+    }
+}
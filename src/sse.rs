@@ -0,0 +1,229 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+    thread,
+    time::Duration,
+};
+
+/// How often a comment is sent on an otherwise quiet stream. Besides keeping proxies from
+/// timing the connection out, the write is what notices that the client went away.
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(15);
+
+// Events queued ahead of the connection before `EventSender::send` blocks.
+const QUEUE_DEPTH: usize = 16;
+
+pub(crate) type EventProducer = Box<dyn FnOnce(EventSender) + Send + 'static>;
+
+/// A single Server-Sent Event.
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    event: Option<String>,
+    data: String,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Self {
+        Self {
+            data: data.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// The event type, dispatched to `addEventListener(name)` listeners in the browser.
+    pub fn set_event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    /// The event id, sent back by the browser in `Last-Event-ID` when it reconnects.
+    pub fn set_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// How long the browser waits before reconnecting after the stream is lost.
+    pub fn set_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut frame = String::new();
+        if let Some(event) = &self.event {
+            frame.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            frame.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(retry) = self.retry {
+            frame.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        // every line of the payload needs its own field, the browser joins them back
+        for line in self.data.split('\n') {
+            frame.push_str(&format!(
+                "data: {}\n",
+                line.strip_suffix('\r').unwrap_or(line)
+            ));
+        }
+        frame.push('\n');
+        frame.into_bytes()
+    }
+}
+
+/// Returned by [`EventSender`] once the client has disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+impl Display for Disconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the client disconnected")
+    }
+}
+
+impl Error for Disconnected {}
+
+/// Writes events to a stream started with [`crate::response::Response::sse`].
+pub struct EventSender {
+    sender: SyncSender<Vec<u8>>,
+    last_event_id: Option<String>,
+}
+
+impl EventSender {
+    /// Queues `event` for the client. Blocks while the connection is behind and fails
+    /// once the client is gone, at which point the producer should return.
+    pub fn send(&self, event: Event) -> Result<(), Disconnected> {
+        self.sender.send(event.encode()).map_err(|_| Disconnected)
+    }
+
+    /// Shorthand for sending an event that only carries `data`.
+    pub fn data(&self, data: &str) -> Result<(), Disconnected> {
+        self.send(Event::new(data))
+    }
+
+    /// Sends a comment line, ignored by the browser.
+    pub fn comment(&self, comment: &str) -> Result<(), Disconnected> {
+        self.sender
+            .send(format!(": {}\n\n", single_line(comment)).into_bytes())
+            .map_err(|_| Disconnected)
+    }
+
+    /// The id of the last event the client received before reconnecting, taken from the
+    /// `Last-Event-ID` request header. Producers should resume right after it.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+}
+
+/// The body of an event stream. The producer runs on its own thread so heartbeats keep
+/// flowing while it waits for something to send.
+pub(crate) struct EventStream {
+    producer: Option<(EventProducer, SyncSender<Vec<u8>>)>,
+    receiver: Receiver<Vec<u8>>,
+    last_event_id: Option<String>,
+    heartbeat: Duration,
+}
+
+impl EventStream {
+    pub fn new(
+        producer: EventProducer,
+        last_event_id: Option<String>,
+        heartbeat: Duration,
+    ) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_DEPTH);
+        Self {
+            producer: Some((producer, sender)),
+            receiver,
+            last_event_id,
+            heartbeat,
+        }
+    }
+}
+
+impl Iterator for EventStream {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // started lazily, a response that is never sent doesn't run the producer
+        if let Some((producer, sender)) = self.producer.take() {
+            let events = EventSender {
+                sender,
+                last_event_id: self.last_event_id.take(),
+            };
+            thread::spawn(move || producer(events));
+        }
+        match self.receiver.recv_timeout(self.heartbeat) {
+            Ok(frame) => Some(frame),
+            Err(RecvTimeoutError::Timeout) => Some(b": heartbeat\n\n".to_vec()),
+            // the producer returned, end the stream
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+// A line break would end the field early and let the rest be read as another field.
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Event, EventStream};
+
+    #[test]
+    fn encodes_events() {
+        let event = Event::new("first\nsecond")
+            .set_event("update")
+            .set_id("7")
+            .set_retry(Duration::from_secs(3));
+        assert_eq!(
+            String::from_utf8(event.encode()).unwrap(),
+            "event: update\nid: 7\nretry: 3000\ndata: first\ndata: second\n\n"
+        );
+        let event = Event::new("").set_event("bad\nname");
+        assert_eq!(
+            String::from_utf8(event.encode()).unwrap(),
+            "event: bad name\ndata: \n\n"
+        );
+    }
+
+    #[test]
+    fn streams_events_and_heartbeats() {
+        let stream = EventStream::new(
+            Box::new(|events| {
+                let resume_from = events.last_event_id().unwrap().parse::<u32>().unwrap();
+                events.data(&(resume_from + 1).to_string()).unwrap();
+                std::thread::sleep(Duration::from_millis(100));
+            }),
+            Some("41".to_string()),
+            Duration::from_millis(20),
+        );
+        let frames = stream
+            .map(|frame| String::from_utf8(frame).unwrap())
+            .collect::<Vec<String>>();
+        assert_eq!(frames[0], "data: 42\n\n");
+        assert!(frames[1..].iter().all(|frame| frame == ": heartbeat\n\n"));
+        assert!(frames.len() > 1);
+    }
+
+    #[test]
+    fn senders_notice_the_stream_was_dropped() {
+        let (done_sender, done) = std::sync::mpsc::channel();
+        let mut stream = EventStream::new(
+            Box::new(move |events| {
+                events.data("hello").unwrap();
+                while events.comment("ping").is_ok() {}
+                done_sender.send(()).unwrap();
+            }),
+            None,
+            Duration::from_secs(5),
+        );
+        assert_eq!(stream.next().unwrap(), b"data: hello\n\n");
+        drop(stream);
+        done.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
@@ -0,0 +1,545 @@
+use std::{
+    fs::{File, Metadata},
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    body::Body,
+    http_date::{format_http_date, parse_http_date, truncate_to_seconds},
+    request::{Request, percent_decode_bytes},
+    response::Response,
+};
+
+pub(crate) fn static_handler(
+    root_path: &str,
+    folder_path: Arc<PathBuf>,
+    request: &Request,
+    response: Response,
+) -> Response {
+    if !folder_path.exists() {
+        return response.set_body("Not Found").set_status(404);
+    }
+
+    if !folder_path.is_dir() {
+        return response.set_body("Resource type conflict").set_status(409);
+    }
+
+    let resource = if request.path.starts_with(root_path) {
+        request.path.strip_prefix(root_path).unwrap()
+    } else {
+        request.path.as_str()
+    };
+
+    let requested_resource = match resolve_resource(&folder_path, resource) {
+        Ok(requested_resource) => requested_resource,
+        Err(status) => return rejected(response, status),
+    };
+
+    if !requested_resource.exists() {
+        return response.set_body("Not Found").set_status(404);
+    }
+
+    // symlinks inside the folder could still point outside of it
+    match (
+        folder_path.canonicalize(),
+        requested_resource.canonicalize(),
+    ) {
+        (Ok(root), Ok(resolved)) if resolved.starts_with(&root) => {}
+        (Ok(_), Ok(_)) => return rejected(response, 403),
+        _ => return response.set_body("Not Found").set_status(404),
+    }
+
+    if !requested_resource.is_file() {
+        return response
+            .set_body("Invalid resource requested")
+            .set_status(409);
+    }
+
+    let content_type = match requested_resource.extension() {
+        Some(extension) => match extension.to_str().unwrap() {
+            "css" => "text/css",
+            "js" => "text/javascript",
+            "htm" => "text/html",
+            "html" => "text/html",
+            "xml" => "text/xml",
+            "jpg" => "image/jpeg",
+            "png" => "image/png",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "avif" => "image/avif",
+            "svg" => "image/svg+xml",
+            "ico" => "image/vnd.microsoft.icon",
+            "pdf" => "application/pdf",
+            "mp3" => "audio/mpeg",
+            "mp4" => "video/mp4",
+            "webm" => "video/webm",
+            _ => "application/octet-stream",
+        },
+        None => "application/octed-stream",
+    };
+
+    let response = response
+        .add_header("Content-Type", content_type)
+        .add_header("Accept-Ranges", "bytes");
+
+    // the metadata is read from the opened file so it matches the bytes being sent
+    let (file, metadata) = match File::open(&requested_resource)
+        .and_then(|file| file.metadata().map(|metadata| (file, metadata)))
+    {
+        Ok(opened) => opened,
+        Err(err) => return response.set_body(&err.to_string()).set_status(500),
+    };
+    let length = metadata.len();
+    let validators = Validators::from_metadata(&metadata);
+    let mut response = response.add_header("ETag", &validators.etag);
+    if let Some(last_modified) = validators.last_modified {
+        response = response.add_header("Last-Modified", &format_http_date(last_modified));
+    }
+
+    match evaluate_preconditions(request, &validators) {
+        Precondition::Failed => {
+            return response
+                .add_header("Content-Type", "text/plain")
+                .set_body("Precondition Failed")
+                .set_status(412);
+        }
+        Precondition::NotModified => return response.set_status(304),
+        Precondition::Passed => {}
+    }
+
+    if let Some(range) = request.header("Range")
+        && if_range_matches(request, &validators)
+    {
+        match parse_range(range, length) {
+            ByteRanges::Satisfiable(ranges) => {
+                return match partial_body(content_type, &requested_resource, file, length, &ranges)
+                {
+                    Ok(((header_name, header_value), body)) => response
+                        .add_header(header_name, &header_value)
+                        .set_body_from(body)
+                        .set_status(206),
+                    Err(err) => response.set_body(&err.to_string()).set_status(500),
+                };
+            }
+            ByteRanges::Unsatisfiable => {
+                return response
+                    .add_header("Content-Type", "text/plain")
+                    .add_header("Content-Range", &format!("bytes */{length}"))
+                    .set_body("Range Not Satisfiable")
+                    .set_status(416);
+            }
+            ByteRanges::Ignored => {}
+        }
+    }
+
+    // small files are kept in memory so they can still be compressed on the way out
+    if length <= BUFFERED_FILE_LIMIT {
+        let mut content = Vec::with_capacity(length as usize);
+        return match file.take(length).read_to_end(&mut content) {
+            Ok(_) => response.set_body_bytes(&content).set_status(200),
+            Err(err) => response.set_body(&err.to_string()).set_status(500),
+        };
+    }
+    response.set_body_reader(file, length).set_status(200)
+}
+
+// Files up to this size are read into memory, anything larger is streamed.
+const BUFFERED_FILE_LIMIT: u64 = 1024 * 1024;
+
+// Requests asking for more ranges than this get the whole file instead.
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRanges {
+    /// Inclusive `(first, last)` byte positions.
+    Satisfiable(Vec<(u64, u64)>),
+    Unsatisfiable,
+    /// The header is malformed or uses another unit, the full file is served.
+    Ignored,
+}
+
+/// Parses a `Range: bytes=...` header against a representation of `length` bytes.
+fn parse_range(header: &str, length: u64) -> ByteRanges {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        return ByteRanges::Ignored;
+    };
+    let mut ranges = vec![];
+    for spec in specs.split(',') {
+        let Some((first, last)) = spec.trim().split_once('-') else {
+            return ByteRanges::Ignored;
+        };
+        let range = match (first.trim(), last.trim()) {
+            // suffix range, the last N bytes
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => None,
+                Ok(suffix) => Some((length.saturating_sub(suffix), length.checked_sub(1))),
+                Err(_) => return ByteRanges::Ignored,
+            },
+            (first, last) => {
+                let Ok(first) = first.parse::<u64>() else {
+                    return ByteRanges::Ignored;
+                };
+                let last = match last {
+                    "" => length.checked_sub(1),
+                    last => match last.parse::<u64>() {
+                        Ok(last) if last >= first => Some(last.min(length.saturating_sub(1))),
+                        _ => return ByteRanges::Ignored,
+                    },
+                };
+                (first < length).then_some((first, last))
+            }
+        };
+        if let Some((first, Some(last))) = range {
+            ranges.push((first, last));
+        }
+    }
+    if ranges.len() > MAX_RANGES {
+        return ByteRanges::Ignored;
+    }
+    if ranges.is_empty() {
+        return ByteRanges::Unsatisfiable;
+    }
+    ByteRanges::Satisfiable(ranges)
+}
+
+/// Validators describing the current version of a static file.
+struct Validators {
+    etag: String,
+    last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    fn from_metadata(metadata: &Metadata) -> Self {
+        let modified = metadata.modified().ok();
+        let modified_nanos = modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_nanos())
+            .unwrap_or_default();
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Self {
+            etag: format!("\"{:x}-{:x}-{:x}\"", metadata.len(), modified_nanos, inode),
+            last_modified: modified.map(truncate_to_seconds),
+        }
+    }
+
+    fn modified_after(&self, date: SystemTime) -> bool {
+        self.last_modified
+            .is_some_and(|last_modified| last_modified > date)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Precondition {
+    Passed,
+    NotModified,
+    Failed,
+}
+
+/// Evaluates the conditional request headers in the order given by RFC 9110, section 13.2.2.
+fn evaluate_preconditions(request: &Request, validators: &Validators) -> Precondition {
+    if let Some(if_match) = request.header("If-Match") {
+        if !etag_list_matches(if_match, &validators.etag, true) {
+            return Precondition::Failed;
+        }
+    } else if let Some(date) = request
+        .header("If-Unmodified-Since")
+        .and_then(parse_http_date)
+        && validators.modified_after(date)
+    {
+        return Precondition::Failed;
+    }
+
+    if let Some(if_none_match) = request.header("If-None-Match") {
+        if etag_list_matches(if_none_match, &validators.etag, false) {
+            return Precondition::NotModified;
+        }
+    } else if let Some(date) = request
+        .header("If-Modified-Since")
+        .and_then(parse_http_date)
+        && !validators.modified_after(date)
+        && validators.last_modified.is_some()
+    {
+        return Precondition::NotModified;
+    }
+    Precondition::Passed
+}
+
+/// Matches `etag` against an `If-Match`/`If-None-Match` field value. `If-Match` uses
+/// the strong comparison, where weak tags never match, `If-None-Match` the weak one.
+fn etag_list_matches(field_value: &str, etag: &str, strong: bool) -> bool {
+    if field_value.trim() == "*" {
+        return true;
+    }
+    field_value.split(',').map(str::trim).any(|candidate| {
+        if strong {
+            candidate == etag
+        } else {
+            candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+        }
+    })
+}
+
+/// A `Range` header only applies when `If-Range` is absent or still matches the file.
+fn if_range_matches(request: &Request, validators: &Validators) -> bool {
+    let Some(if_range) = request.header("If-Range").map(str::trim) else {
+        return true;
+    };
+    if if_range.starts_with('"') {
+        return if_range == validators.etag;
+    }
+    match (parse_http_date(if_range), validators.last_modified) {
+        (Some(date), Some(last_modified)) => last_modified == date,
+        _ => false,
+    }
+}
+
+/// The body of a 206 response along with the header describing it, `Content-Range` for
+/// a single range and the multipart `Content-Type` for several.
+fn partial_body(
+    content_type: &str,
+    path: &Path,
+    mut file: File,
+    length: u64,
+    ranges: &[(u64, u64)],
+) -> io::Result<((&'static str, String), Body)> {
+    if let [(first, last)] = ranges {
+        file.seek(SeekFrom::Start(*first))?;
+        return Ok((
+            ("Content-Range", format!("bytes {first}-{last}/{length}")),
+            Body::from_reader_with_length(file, last - first + 1),
+        ));
+    }
+
+    // every part is read from its own handle, cloned handles would share the position
+    let boundary = multipart_boundary();
+    let mut body: Box<dyn Read + Send> = Box::new(io::empty());
+    let mut body_length = 0;
+    for (first, last) in ranges {
+        let part_head = format!(
+            "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: bytes {first}-{last}/{length}\r\n\r\n"
+        );
+        let mut part = File::open(path)?;
+        part.seek(SeekFrom::Start(*first))?;
+        body_length += part_head.len() as u64 + last - first + 1;
+        body = Box::new(
+            body.chain(Cursor::new(part_head.into_bytes()))
+                .chain(part.take(last - first + 1)),
+        );
+    }
+    let closing = format!("\r\n--{boundary}--\r\n");
+    body_length += closing.len() as u64;
+    body = Box::new(body.chain(Cursor::new(closing.into_bytes())));
+
+    Ok((
+        (
+            "Content-Type",
+            format!("multipart/byteranges; boundary={boundary}"),
+        ),
+        Body::from_reader_with_length(body, body_length),
+    ))
+}
+
+fn multipart_boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    format!(
+        "simple_http_server_{:08x}{:08x}",
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Maps the url below the static route onto a path inside `folder_path`.
+///
+/// Every segment is percent-decoded on its own, so an encoded separator can't be used
+/// to smuggle in extra path components. `..` segments, NUL bytes and segments that
+/// would not end up as a plain file name are refused with the status to answer with.
+fn resolve_resource(folder_path: &Path, resource: &str) -> Result<PathBuf, u16> {
+    let mut requested_resource = folder_path.to_path_buf();
+    let mut is_index = true;
+    for segment in resource.split('/') {
+        let decoded = percent_decode_bytes(segment);
+        if decoded.iter().any(|byte| matches!(byte, 0 | b'/' | b'\\')) {
+            return Err(403);
+        }
+        let segment = String::from_utf8(decoded).map_err(|_| 404u16)?;
+        match segment.as_str() {
+            "" | "." => continue,
+            ".." => return Err(403),
+            _ => {}
+        }
+        // on Windows a segment like `C:` would replace the whole path when joined
+        let mut components = Path::new(&segment).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(403);
+        }
+        requested_resource.push(segment);
+        is_index = false;
+    }
+
+    // determine if the request was for the index file
+    if is_index {
+        requested_resource.push("index.html");
+    }
+    Ok(requested_resource)
+}
+
+fn rejected(response: Response, status: u16) -> Response {
+    match status {
+        403 => response.set_body("Forbidden").set_status(403),
+        _ => response.set_body("Not Found").set_status(404),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{
+        ByteRanges, Precondition, Validators, etag_list_matches, evaluate_preconditions,
+        parse_range, resolve_resource,
+    };
+    use crate::request::Request;
+
+    #[test]
+    fn resolves_resources_inside_the_folder() {
+        let root = Path::new("/srv/static");
+        assert_eq!(resolve_resource(root, ""), Ok(root.join("index.html")));
+        assert_eq!(resolve_resource(root, "/"), Ok(root.join("index.html")));
+        assert_eq!(
+            resolve_resource(root, "/css/style.css"),
+            Ok(root.join("css").join("style.css"))
+        );
+        assert_eq!(
+            resolve_resource(root, "/./css//style.css"),
+            Ok(root.join("css").join("style.css"))
+        );
+        assert_eq!(
+            resolve_resource(root, "/my%20photo.jpg"),
+            Ok(root.join("my photo.jpg"))
+        );
+    }
+
+    #[test]
+    fn refuses_traversal() {
+        let root = Path::new("/srv/static");
+        assert_eq!(resolve_resource(root, "/../../etc/passwd"), Err(403));
+        assert_eq!(resolve_resource(root, "/css/../../secret"), Err(403));
+        assert_eq!(resolve_resource(root, "/%2e%2e/secret"), Err(403));
+        assert_eq!(resolve_resource(root, "/..%2fsecret"), Err(403));
+        assert_eq!(resolve_resource(root, "/..%5csecret"), Err(403));
+        assert_eq!(resolve_resource(root, "/%2fetc%2fpasswd"), Err(403));
+        assert_eq!(resolve_resource(root, "/index.html%00.png"), Err(403));
+        assert_eq!(resolve_resource(root, "/%ff%fe"), Err(404));
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-499", 1000),
+            ByteRanges::Satisfiable(vec![(0, 499)])
+        );
+        assert_eq!(
+            parse_range("bytes=500-", 1000),
+            ByteRanges::Satisfiable(vec![(500, 999)])
+        );
+        assert_eq!(
+            parse_range("bytes=-200", 1000),
+            ByteRanges::Satisfiable(vec![(800, 999)])
+        );
+        assert_eq!(
+            parse_range("bytes=900-2000, 0-0", 1000),
+            ByteRanges::Satisfiable(vec![(900, 999), (0, 0)])
+        );
+        assert_eq!(
+            parse_range("bytes=-5000", 1000),
+            ByteRanges::Satisfiable(vec![(0, 999)])
+        );
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRanges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRanges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRanges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=5-1", 1000), ByteRanges::Ignored);
+        assert_eq!(parse_range("items=0-1", 1000), ByteRanges::Ignored);
+        assert_eq!(parse_range("bytes=a-b", 1000), ByteRanges::Ignored);
+    }
+
+    #[test]
+    fn compares_entity_tags() {
+        assert!(etag_list_matches("\"a\"", "\"a\"", true));
+        assert!(etag_list_matches("\"b\", \"a\"", "\"a\"", true));
+        assert!(etag_list_matches("*", "\"a\"", true));
+        assert!(!etag_list_matches("W/\"a\"", "\"a\"", true));
+        assert!(etag_list_matches("W/\"a\"", "\"a\"", false));
+        assert!(!etag_list_matches("\"b\"", "\"a\"", false));
+    }
+
+    #[test]
+    fn evaluates_preconditions_in_order() {
+        let validators = Validators {
+            etag: "\"abc\"".into(),
+            last_modified: Some(UNIX_EPOCH + Duration::from_secs(784_111_777)),
+        };
+        let check = |headers: &[(&str, &str)]| {
+            let raw = headers
+                .iter()
+                .map(|(name, value)| format!("{name}: {value}\r\n"))
+                .collect::<String>();
+            let raw = format!("GET / HTTP/1.1\r\n{raw}\r\n");
+            let request = Request::from_reader(&mut raw.as_bytes(), 0).unwrap();
+            evaluate_preconditions(&request, &validators)
+        };
+        let before = "Sat, 05 Nov 1994 08:49:37 GMT";
+        let exact = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+        assert_eq!(check(&[]), Precondition::Passed);
+        assert_eq!(check(&[("If-Match", "\"abc\"")]), Precondition::Passed);
+        assert_eq!(check(&[("If-Match", "\"xyz\"")]), Precondition::Failed);
+        assert_eq!(
+            check(&[("If-Unmodified-Since", before)]),
+            Precondition::Failed
+        );
+        // If-Match takes precedence over If-Unmodified-Since
+        assert_eq!(
+            check(&[("If-Match", "\"abc\""), ("If-Unmodified-Since", before)]),
+            Precondition::Passed
+        );
+        assert_eq!(
+            check(&[("If-None-Match", "W/\"abc\"")]),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            check(&[("If-Modified-Since", exact)]),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            check(&[("If-Modified-Since", before)]),
+            Precondition::Passed
+        );
+        // If-None-Match takes precedence over If-Modified-Since
+        assert_eq!(
+            check(&[("If-None-Match", "\"xyz\""), ("If-Modified-Since", exact)]),
+            Precondition::Passed
+        );
+        assert_eq!(
+            check(&[("If-Match", "\"xyz\""), ("If-None-Match", "\"abc\"")]),
+            Precondition::Failed
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use rustls::{
+    ServerConfig, ServerConnection, StreamOwned,
+    crypto::{CryptoProvider, ring},
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+use crate::{connection::Stream, http2};
+
+#[derive(Clone)]
+struct CertificatePaths {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+/// Where the certificates configured on [`crate::App`] are loaded from.
+#[derive(Clone, Default)]
+pub(crate) struct TlsSettings {
+    default: Option<CertificatePaths>,
+    by_name: Vec<(String, CertificatePaths)>,
+}
+
+impl TlsSettings {
+    pub fn set_default(&mut self, cert: &Path, key: &Path) {
+        self.default = Some(CertificatePaths {
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+        });
+    }
+
+    pub fn add_server_name(&mut self, server_name: &str, cert: &Path, key: &Path) {
+        self.by_name.push((
+            server_name.to_ascii_lowercase(),
+            CertificatePaths {
+                cert: cert.to_path_buf(),
+                key: key.to_path_buf(),
+            },
+        ));
+    }
+}
+
+#[derive(Debug, Default)]
+struct Certificates {
+    default: Option<Arc<CertifiedKey>>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/// Picks the certificate for the server name the client asked for (SNI), falling back
+/// to the default certificate.
+#[derive(Debug, Default)]
+struct CertificateResolver {
+    certificates: RwLock<Certificates>,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.read().unwrap();
+        let by_name = client_hello.server_name().and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            // `*.example.com` covers a single label in front of `example.com`
+            let wildcard = name
+                .split_once('.')
+                .map(|(_, parent)| format!("*.{parent}"));
+            certificates
+                .by_name
+                .get(&name)
+                .or_else(|| wildcard.and_then(|wildcard| certificates.by_name.get(&wildcard)))
+        });
+        by_name.or(certificates.default.as_ref()).cloned()
+    }
+}
+
+/// Wraps accepted connections in a TLS session. Certificates are read when the server
+/// starts and can be read again with [`TlsAcceptor::reload`] while it runs.
+pub(crate) struct TlsAcceptor {
+    settings: TlsSettings,
+    provider: Arc<CryptoProvider>,
+    resolver: Arc<CertificateResolver>,
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    pub fn new(settings: TlsSettings) -> io::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let resolver = Arc::new(CertificateResolver::default());
+        let mut config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        config.alpn_protocols = vec![http2::ALPN.to_vec(), b"http/1.1".to_vec()];
+        let acceptor = Self {
+            settings,
+            provider,
+            resolver,
+            config: Arc::new(config),
+        };
+        acceptor.reload()?;
+        Ok(acceptor)
+    }
+
+    /// Reads every certificate and key again. New connections use the new certificates
+    /// while established ones keep their session. Nothing changes if any file fails to
+    /// load.
+    pub fn reload(&self) -> io::Result<()> {
+        let default = match &self.settings.default {
+            Some(paths) => Some(self.load(paths)?),
+            None => None,
+        };
+        let by_name = self
+            .settings
+            .by_name
+            .iter()
+            .map(|(name, paths)| Ok((name.clone(), self.load(paths)?)))
+            .collect::<io::Result<HashMap<String, Arc<CertifiedKey>>>>()?;
+        *self.resolver.certificates.write().unwrap() = Certificates { default, by_name };
+        Ok(())
+    }
+
+    /// Starts a TLS session on `stream`. The handshake itself happens on the first read,
+    /// under the read timeout already set on the socket.
+    pub fn accept(&self, stream: TcpStream) -> io::Result<TlsStream> {
+        let connection = ServerConnection::new(self.config.clone()).map_err(invalid_data)?;
+        Ok(TlsStream(Some(StreamOwned::new(connection, stream))))
+    }
+
+    fn load(&self, paths: &CertificatePaths) -> io::Result<Arc<CertifiedKey>> {
+        let with_path = |path: &Path, err: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {err}", path.display()),
+            )
+        };
+        let chain = CertificateDer::pem_file_iter(&paths.cert)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| with_path(&paths.cert, err.to_string()))?;
+        if chain.is_empty() {
+            return Err(with_path(&paths.cert, "no certificate found".into()));
+        }
+        let key = PrivateKeyDer::from_pem_file(&paths.key)
+            .map_err(|err| with_path(&paths.key, err.to_string()))?;
+        let certified_key = CertifiedKey::from_der(chain, key, &self.provider)
+            .map_err(|err| with_path(&paths.key, err.to_string()))?;
+        Ok(Arc::new(certified_key))
+    }
+}
+
+/// A server side TLS session over an accepted TCP connection. The session is only
+/// taken out when the stream is split.
+pub(crate) struct TlsStream(Option<StreamOwned<ServerConnection, TcpStream>>);
+
+impl TlsStream {
+    fn session(&self) -> &StreamOwned<ServerConnection, TcpStream> {
+        self.0.as_ref().expect("the TLS stream was split")
+    }
+
+    fn session_mut(&mut self) -> &mut StreamOwned<ServerConnection, TcpStream> {
+        self.0.as_mut().expect("the TLS stream was split")
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.session_mut().read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.session_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session_mut().flush()
+    }
+}
+
+impl Stream for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.session().sock.set_read_timeout(timeout)
+    }
+
+    fn is_secure(&self) -> bool {
+        true
+    }
+
+    fn alpn_protocol(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let session = self.session_mut();
+        while session.conn.is_handshaking() {
+            session.conn.complete_io(&mut session.sock)?;
+        }
+        Ok(session.conn.alpn_protocol().map(<[u8]>::to_vec))
+    }
+
+    fn split(mut self) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        let reader_sock = self.session().sock.try_clone()?;
+        let StreamOwned { conn, sock } = self.0.take().expect("the TLS stream was split");
+        let conn = Arc::new(Mutex::new(conn));
+        let reader = TlsReader {
+            conn: conn.clone(),
+            sock: reader_sock,
+            incoming: Vec::new(),
+        };
+        Ok((Box::new(reader), Box::new(TlsWriter { conn, sock })))
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        // tell the client the connection ends here rather than being cut off
+        if let Some(session) = &mut self.0 {
+            session.conn.send_close_notify();
+            let _ = session.conn.write_tls(&mut session.sock);
+        }
+    }
+}
+
+/// The reading half of a split [`TlsStream`]. Records the session has to send in
+/// return, e.g. for a key update, go out with the next write on the other half.
+struct TlsReader {
+    conn: Arc<Mutex<ServerConnection>>,
+    sock: TcpStream,
+    // bytes read off the socket that the session didn't take yet
+    incoming: Vec<u8>,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut conn = self.conn.lock().unwrap();
+                match conn.reader().read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                if !self.incoming.is_empty() {
+                    let mut incoming = &self.incoming[..];
+                    conn.read_tls(&mut incoming)?;
+                    let consumed = self.incoming.len() - incoming.len();
+                    self.incoming.drain(..consumed);
+                    conn.process_new_packets().map_err(invalid_data)?;
+                    continue;
+                }
+            }
+            // the socket is read without holding the lock so the other half can write
+            let mut buffer = [0; 16 * 1024];
+            let read = self.sock.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(0);
+            }
+            self.incoming.extend_from_slice(&buffer[..read]);
+        }
+    }
+}
+
+/// The writing half of a split [`TlsStream`]. Dropping it ends the session.
+struct TlsWriter {
+    conn: Arc<Mutex<ServerConnection>>,
+    sock: TcpStream,
+}
+
+impl TlsWriter {
+    /// Sends the records the session has queued up, outside of the lock.
+    fn send_records(&mut self) -> io::Result<()> {
+        let mut records = Vec::new();
+        {
+            let mut conn = self.conn.lock().unwrap();
+            while conn.wants_write() {
+                conn.write_tls(&mut records)?;
+            }
+        }
+        self.sock.write_all(&records)
+    }
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.conn.lock().unwrap().writer().write(buf)?;
+        self.send_records()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.lock().unwrap().writer().flush()?;
+        self.send_records()
+    }
+}
+
+impl Drop for TlsWriter {
+    fn drop(&mut self) {
+        self.conn.lock().unwrap().send_close_notify();
+        let _ = self.send_records();
+        let _ = self.sock.shutdown(Shutdown::Both);
+    }
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
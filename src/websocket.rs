@@ -0,0 +1,664 @@
+use std::{error::Error, fmt::Display, io, sync::Arc, time::Duration};
+
+use crate::{
+    WebSocketHandler, base64,
+    connection::{Stream, Upgraded},
+    request::Request,
+    response::Response,
+    sha1::sha1,
+};
+
+// Appended to the client key before hashing, RFC 6455 section 1.3.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Messages larger than this are refused with close code 1009 unless changed with
+/// [`WebSocket::set_max_message_size`].
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+// How long `WebSocket::close` waits for the client to answer the close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+// Control frames can't be fragmented and carry at most this many bytes.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const INVALID_PAYLOAD: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Pings are answered with a pong automatically, they are only surfaced so the
+    /// application can track liveness.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The closing handshake started. `None` when the peer didn't give a close code.
+    Close(Option<CloseFrame>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+/// Everything that can end a WebSocket conversation.
+#[derive(Debug)]
+pub enum WebSocketError {
+    /// The closing handshake completed or the client went away.
+    Closed,
+    /// The client broke the protocol, the connection was closed with 1002.
+    Protocol(&'static str),
+    /// A text message or close reason wasn't valid UTF-8, the connection was closed
+    /// with 1007.
+    InvalidUtf8,
+    /// A message exceeded the maximum message size, the connection was closed with 1009.
+    MessageTooLarge,
+    Io(io::Error),
+}
+
+impl WebSocketError {
+    fn from_io(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => WebSocketError::Closed,
+            _ => WebSocketError::Io(err),
+        }
+    }
+
+    // A read that ran into the timeout of `WebSocket::set_read_timeout`.
+    fn is_timeout(&self) -> bool {
+        matches!(self, WebSocketError::Io(err)
+            if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut))
+    }
+
+    // The close code the client is told about before the connection is dropped.
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            WebSocketError::Protocol(_) => Some(close_code::PROTOCOL_ERROR),
+            WebSocketError::InvalidUtf8 => Some(close_code::INVALID_PAYLOAD),
+            WebSocketError::MessageTooLarge => Some(close_code::MESSAGE_TOO_BIG),
+            WebSocketError::Closed | WebSocketError::Io(_) => None,
+        }
+    }
+}
+
+impl Display for WebSocketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebSocketError::Closed => f.write_str("the WebSocket connection is closed"),
+            WebSocketError::Protocol(reason) => write!(f, "WebSocket protocol error: {reason}"),
+            WebSocketError::InvalidUtf8 => f.write_str("invalid UTF-8 in a WebSocket message"),
+            WebSocketError::MessageTooLarge => f.write_str("WebSocket message too large"),
+            WebSocketError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Error for WebSocketError {}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// A WebSocket connection handed to the handler of an [`crate::App::ws`] route.
+///
+/// The handler owns the connection for as long as it runs, and the connection is
+/// closed once it returns.
+pub struct WebSocket {
+    stream: Box<dyn Stream>,
+    // bytes the client sent right after the handshake, read before the stream
+    buffered: Vec<u8>,
+    // bytes of the frame being read, put back in `buffered` when a read times out midway
+    partial: Vec<u8>,
+    // opcode and payload of a fragmented message still being received
+    fragments: Option<(u8, Vec<u8>)>,
+    max_message_size: usize,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl WebSocket {
+    pub(crate) fn new(upgraded: Upgraded) -> Self {
+        // the keep-alive timeout doesn't apply, a socket may sit quiet for a long time
+        if let Err(err) = upgraded.stream.set_read_timeout(None) {
+            log::warn!("Failed to clear the WebSocket read timeout: {err}");
+        }
+        Self {
+            stream: upgraded.stream,
+            buffered: upgraded.buffered,
+            partial: vec![],
+            fragments: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    /// Makes [`WebSocket::recv`] fail with a `WouldBlock` or `TimedOut` error when
+    /// nothing arrives within `timeout`. The connection stays open, and a message cut
+    /// short by the timeout is picked up again by the next call.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Waits for the next message. Fragmented messages are reassembled, pings are
+    /// answered and a close frame from the client is acknowledged before it is returned.
+    /// Once the connection is closed every call fails with [`WebSocketError::Closed`].
+    pub fn recv(&mut self) -> Result<Message, WebSocketError> {
+        if self.close_received {
+            return Err(WebSocketError::Closed);
+        }
+        match self.read_message() {
+            Ok(message) => Ok(message),
+            Err(err) if err.is_timeout() => {
+                let mut unread = std::mem::take(&mut self.partial);
+                unread.append(&mut self.buffered);
+                self.buffered = unread;
+                Err(err)
+            }
+            Err(err) => {
+                if let Some(code) = err.close_code()
+                    && !self.close_sent
+                {
+                    let _ = self.send_close(code, "");
+                }
+                // nothing more can be read reliably, don't wait for a close frame
+                self.close_received = true;
+                Err(err)
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: Message) -> Result<(), WebSocketError> {
+        if self.close_sent {
+            return Err(WebSocketError::Closed);
+        }
+        match message {
+            Message::Text(text) => self.write_frame(OP_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(OP_BINARY, &data),
+            Message::Ping(data) => self.write_control_frame(OP_PING, &data),
+            Message::Pong(data) => self.write_control_frame(OP_PONG, &data),
+            Message::Close(frame) => match frame {
+                Some(frame) => self.send_close(frame.code, &frame.reason),
+                None => {
+                    self.close_sent = true;
+                    self.write_frame(OP_CLOSE, &[])
+                }
+            },
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), WebSocketError> {
+        self.send(Message::Text(text.to_string()))
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<(), WebSocketError> {
+        self.send(Message::Binary(data.to_vec()))
+    }
+
+    /// Starts the closing handshake and waits for the client to acknowledge it. Any
+    /// message still arriving in the meantime is dropped.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        if !self.close_sent {
+            self.send_close(code, reason)?;
+        }
+        let _ = self.stream.set_read_timeout(Some(CLOSE_TIMEOUT));
+        while !self.close_received {
+            if self.read_message().is_err() {
+                break;
+            }
+        }
+        self.close_received = true;
+        Ok(())
+    }
+
+    fn send_close(&mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(WebSocketError::Protocol("close reason too long"));
+        }
+        self.close_sent = true;
+        self.write_frame(OP_CLOSE, &payload)
+    }
+
+    fn read_message(&mut self) -> Result<Message, WebSocketError> {
+        loop {
+            let frame = self.read_frame()?;
+            match frame.opcode {
+                OP_CONTINUATION => {
+                    let Some((_, data)) = &mut self.fragments else {
+                        return Err(WebSocketError::Protocol("unexpected continuation frame"));
+                    };
+                    if data.len() + frame.payload.len() > self.max_message_size {
+                        return Err(WebSocketError::MessageTooLarge);
+                    }
+                    data.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        let (opcode, data) = self.fragments.take().unwrap_or_default();
+                        return data_message(opcode, data);
+                    }
+                }
+                OP_TEXT | OP_BINARY => {
+                    if self.fragments.is_some() {
+                        return Err(WebSocketError::Protocol("expected a continuation frame"));
+                    }
+                    if frame.fin {
+                        return data_message(frame.opcode, frame.payload);
+                    }
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+                OP_PING => {
+                    if !self.close_sent {
+                        self.write_frame(OP_PONG, &frame.payload)?;
+                    }
+                    return Ok(Message::Ping(frame.payload));
+                }
+                OP_PONG => return Ok(Message::Pong(frame.payload)),
+                OP_CLOSE => {
+                    let close_frame = parse_close(&frame.payload)?;
+                    self.close_received = true;
+                    if !self.close_sent {
+                        // echo the status code back, as the RFC recommends
+                        match &close_frame {
+                            Some(close_frame) => self.send_close(close_frame.code, "")?,
+                            None => self.send(Message::Close(None))?,
+                        }
+                    }
+                    return Ok(Message::Close(close_frame));
+                }
+                _ => return Err(WebSocketError::Protocol("unknown opcode")),
+            }
+        }
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, WebSocketError> {
+        let mut head = [0; 2];
+        self.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        if head[0] & 0x70 != 0 {
+            // no extension is negotiated, so the reserved bits must be clear
+            return Err(WebSocketError::Protocol("reserved bits set"));
+        }
+        let opcode = head[0] & 0x0F;
+        if head[1] & 0x80 == 0 {
+            return Err(WebSocketError::Protocol("client frames must be masked"));
+        }
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                self.read_exact(&mut length)?;
+                u64::from(u16::from_be_bytes(length))
+            }
+            127 => {
+                let mut length = [0; 8];
+                self.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => u64::from(length),
+        };
+        if opcode >= OP_CLOSE && (!fin || length > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(WebSocketError::Protocol("invalid control frame"));
+        }
+        if length > self.max_message_size as u64 {
+            return Err(WebSocketError::MessageTooLarge);
+        }
+        let mut mask = [0; 4];
+        self.read_exact(&mut mask)?;
+        let mut payload = vec![0; length as usize];
+        self.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        self.partial.clear();
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), WebSocketError> {
+        let mut filled = buf.len().min(self.buffered.len());
+        buf[..filled].copy_from_slice(&self.buffered[..filled]);
+        self.buffered.drain(..filled);
+        while filled < buf.len() {
+            match self.stream.read(&mut buf[filled..]) {
+                Ok(0) => return Err(WebSocketError::Closed),
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    self.partial.extend_from_slice(&buf[..filled]);
+                    return Err(WebSocketError::from_io(err));
+                }
+            }
+        }
+        self.partial.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn write_control_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(WebSocketError::Protocol("control frame payload too long"));
+        }
+        self.write_frame(opcode, payload)
+    }
+
+    // Server frames are never masked and always sent whole.
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            length @ 0..=125 => frame.push(length as u8),
+            length @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.stream
+            .write_all(&frame)
+            .and_then(|_| self.stream.flush())
+            .map_err(WebSocketError::from_io)
+    }
+}
+
+fn data_message(opcode: u8, data: Vec<u8>) -> Result<Message, WebSocketError> {
+    if opcode == OP_TEXT {
+        String::from_utf8(data)
+            .map(Message::Text)
+            .map_err(|_| WebSocketError::InvalidUtf8)
+    } else {
+        Ok(Message::Binary(data))
+    }
+}
+
+fn parse_close(payload: &[u8]) -> Result<Option<CloseFrame>, WebSocketError> {
+    let (code, reason) = match payload {
+        [] => return Ok(None),
+        [_] => return Err(WebSocketError::Protocol("truncated close code")),
+        [high, low, reason @ ..] => (u16::from_be_bytes([*high, *low]), reason),
+    };
+    // codes below 3000 are reserved for the protocol, and some of those must never
+    // be sent on the wire
+    if !matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999) {
+        return Err(WebSocketError::Protocol("invalid close code"));
+    }
+    let reason = String::from_utf8(reason.to_vec()).map_err(|_| WebSocketError::InvalidUtf8)?;
+    Ok(Some(CloseFrame { code, reason }))
+}
+
+fn has_token(request: &Request, header: &str, token: &str) -> bool {
+    request.header(header).is_some_and(|value| {
+        value
+            .split(',')
+            .any(|part| part.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+fn accept_key(key: &str) -> String {
+    base64::encode(&sha1(format!("{key}{HANDSHAKE_GUID}").as_bytes()))
+}
+
+/// Answers the opening handshake of RFC 6455 section 4.2. Valid requests get a
+/// `101 Switching Protocols` and the connection is handed to `handler` once it is sent.
+pub(crate) fn handshake(
+    request: &Request,
+    response: Response,
+    handler: Arc<WebSocketHandler>,
+) -> Response {
+    if !has_token(request, "Upgrade", "websocket") || !has_token(request, "Connection", "upgrade") {
+        return response
+            .set_status(426)
+            .add_header("Upgrade", "websocket")
+            .set_body("This endpoint only accepts WebSocket connections");
+    }
+    if request.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return response
+            .set_status(426)
+            .add_header("Sec-WebSocket-Version", "13")
+            .set_body("Unsupported WebSocket version");
+    }
+    let key = match request.header("Sec-WebSocket-Key").map(str::trim) {
+        Some(key) if base64::decode(key).is_some_and(|nonce| nonce.len() == 16) => key,
+        _ => {
+            return response
+                .set_status(400)
+                .set_body("Missing or invalid Sec-WebSocket-Key");
+        }
+    };
+
+    let request = request.clone();
+    response
+        .set_status(101)
+        .remove_header("Content-Type")
+        .add_header("Upgrade", "websocket")
+        .add_header("Connection", "Upgrade")
+        .add_header("Sec-WebSocket-Accept", &accept_key(key))
+        .set_upgrade(move |upgraded| handler(&request, WebSocket::new(upgraded)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Cursor, Read, Write},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{CloseFrame, Message, WebSocket, WebSocketError, accept_key};
+    use crate::connection::{Stream, Upgraded};
+
+    // Replays `input` and records everything the server writes.
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Arc<Mutex<Vec<u8>>>,
+        // position in `input` where a read times out once
+        stall_at: Option<u64>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(stall_at) = self.stall_at else {
+                return self.input.read(buf);
+            };
+            let before_stall = (stall_at - self.input.position()) as usize;
+            if before_stall == 0 {
+                self.stall_at = None;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let length = buf.len().min(before_stall);
+            self.input.read(&mut buf[..length])
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Stream for MockStream {
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        frame
+    }
+
+    fn socket(frames: &[Vec<u8>]) -> (WebSocket, Arc<Mutex<Vec<u8>>>) {
+        stalling_socket(frames, None)
+    }
+
+    fn stalling_socket(
+        frames: &[Vec<u8>],
+        stall_at: Option<u64>,
+    ) -> (WebSocket, Arc<Mutex<Vec<u8>>>) {
+        let output = Arc::new(Mutex::new(vec![]));
+        let input = frames.concat();
+        // the first frame arrives together with the handshake and is already buffered
+        let (buffered, rest) = input.split_at(input.len().min(3));
+        let stream = MockStream {
+            input: Cursor::new(rest.to_vec()),
+            output: output.clone(),
+            stall_at,
+        };
+        let upgraded = Upgraded {
+            stream: Box::new(stream),
+            buffered: buffered.to_vec(),
+        };
+        (WebSocket::new(upgraded), output)
+    }
+
+    #[test]
+    fn computes_the_accept_key() {
+        // the example from RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn reassembles_fragments_around_control_frames() {
+        let (mut socket, output) = socket(&[
+            client_frame(false, 0x1, b"Hel"),
+            client_frame(true, 0x9, b"ping"),
+            client_frame(false, 0x0, b"lo, "),
+            client_frame(true, 0x0, "wörld".as_bytes()),
+            client_frame(true, 0x2, &[0; 300]),
+        ]);
+        assert_eq!(socket.recv().unwrap(), Message::Ping(b"ping".to_vec()));
+        assert_eq!(socket.recv().unwrap(), Message::Text("Hello, wörld".into()));
+        assert_eq!(socket.recv().unwrap(), Message::Binary(vec![0; 300]));
+        // the ping was answered with a pong carrying the same payload
+        assert_eq!(*output.lock().unwrap(), b"\x8a\x04ping");
+    }
+
+    #[test]
+    fn writes_unmasked_frames() {
+        let (mut socket, output) = socket(&[]);
+        socket.send_text("hi").unwrap();
+        socket.send_binary(&[7; 200]).unwrap();
+        let output = output.lock().unwrap();
+        assert_eq!(&output[..4], b"\x81\x02hi");
+        assert_eq!(&output[4..8], &[0x82, 126, 0, 200]);
+        assert_eq!(output.len(), 8 + 200);
+    }
+
+    #[test]
+    fn acknowledges_the_closing_handshake() {
+        let mut payload = 1000u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        let (mut socket, output) = socket(&[client_frame(true, 0x8, &payload)]);
+        assert_eq!(
+            socket.recv().unwrap(),
+            Message::Close(Some(CloseFrame {
+                code: 1000,
+                reason: "bye".into()
+            }))
+        );
+        assert_eq!(*output.lock().unwrap(), b"\x88\x02\x03\xe8");
+        assert!(matches!(socket.recv(), Err(WebSocketError::Closed)));
+        assert!(matches!(
+            socket.send_text("late"),
+            Err(WebSocketError::Closed)
+        ));
+    }
+
+    #[test]
+    fn timeouts_leave_the_connection_open() {
+        let frames = [
+            client_frame(true, 0x1, b"hello"),
+            client_frame(true, 0x1, b"again"),
+        ];
+        // the read times out in the middle of the first payload
+        let (mut socket, _) = stalling_socket(&frames, Some(5));
+        let err = socket.recv().unwrap_err();
+        assert!(matches!(err, WebSocketError::Io(err) if err.kind() == io::ErrorKind::WouldBlock));
+        assert_eq!(socket.recv().unwrap(), Message::Text("hello".into()));
+        assert_eq!(socket.recv().unwrap(), Message::Text("again".into()));
+    }
+
+    #[test]
+    fn closes_on_protocol_violations() {
+        let cases = [
+            // unmasked frame
+            (vec![0x81, 0x02, b'h', b'i'], 1002),
+            // reserved bit set
+            (client_frame(true, 0x41, b"hi"), 1002),
+            // continuation without a message to continue
+            (client_frame(true, 0x0, b"hi"), 1002),
+            // fragmented control frame
+            (client_frame(false, 0x9, b"hi"), 1002),
+            // reserved close code
+            (client_frame(true, 0x8, &1005u16.to_be_bytes()), 1002),
+            // text that isn't UTF-8
+            (client_frame(true, 0x1, &[0xff, 0xfe]), 1007),
+        ];
+        for (frame, code) in cases {
+            let (mut socket, output) = socket(&[frame]);
+            assert!(socket.recv().is_err());
+            let mut expected = vec![0x88, 0x02];
+            expected.extend_from_slice(&u16::to_be_bytes(code));
+            assert_eq!(*output.lock().unwrap(), expected);
+        }
+
+        let (mut socket, output) = socket(&[client_frame(true, 0x2, &[0; 20])]);
+        socket.set_max_message_size(10);
+        assert!(matches!(
+            socket.recv(),
+            Err(WebSocketError::MessageTooLarge)
+        ));
+        assert_eq!(*output.lock().unwrap(), b"\x88\x02\x03\xf1");
+    }
+}
@@ -0,0 +1,1253 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use simple_http_server::{
+    App, Router, Server, request::RequestMethod, response::Response, sse::Event, websocket::Message,
+};
+
+fn spawn(app: App) -> Server {
+    app.set_listen_ip("127.0.0.1").spawn().unwrap()
+}
+
+// Sends a raw request and reads until the server closes the connection.
+fn send(addr: SocketAddr, raw_request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(raw_request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn hello_app() -> App {
+    App::new().get("/hello", |_request, response| {
+        response.set_body("Hello, World").set_status(200)
+    })
+}
+
+#[test]
+fn spawned_servers_report_their_address() {
+    let first = spawn(hello_app());
+    let second = spawn(hello_app());
+    assert_ne!(first.local_addr().port(), 0);
+    assert_ne!(first.local_addr(), second.local_addr());
+
+    let response = send(
+        first.local_addr(),
+        "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nHello, World"));
+
+    first.shutdown().unwrap();
+    second.shutdown().unwrap();
+}
+
+#[test]
+fn answers_pipelined_requests_in_order() {
+    let server = spawn(hello_app().get("/bye", |_request, response| {
+        response.set_body("Bye").set_status(200)
+    }));
+    let response = send(
+        server.local_addr(),
+        "GET /hello HTTP/1.1\r\n\r\nGET /bye HTTP/1.1\r\n\r\nGET /hello HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    let hello = response.find("Hello, World").unwrap();
+    let bye = response.find("Bye").unwrap();
+    assert!(hello < bye);
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 3);
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn shutdown_closes_idle_connections_and_stops_accepting() {
+    let server = spawn(hello_app());
+    let addr = server.local_addr();
+    let mut idle = TcpStream::connect(addr).unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    idle.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+    let mut buffer = [0; 1024];
+    assert!(idle.read(&mut buffer).unwrap() > 0);
+
+    assert!(
+        server
+            .shutdown_with_timeout(Duration::from_secs(5))
+            .unwrap()
+    );
+    assert_eq!(idle.read(&mut buffer).unwrap_or(0), 0);
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn shutdown_answers_connections_waiting_for_a_worker() {
+    let server = spawn(
+        hello_app()
+            .set_workers(1)
+            .get("/slow", |_request, _response| {
+                std::thread::sleep(Duration::from_millis(300));
+                "slow"
+            }),
+    );
+    let addr = server.local_addr();
+    let slow =
+        std::thread::spawn(move || send(addr, "GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n"));
+    std::thread::sleep(Duration::from_millis(50));
+    // the only worker is busy, this connection waits in the queue
+    let mut queued = TcpStream::connect(addr).unwrap();
+    queued
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    queued.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+
+    assert!(
+        server
+            .shutdown_with_timeout(Duration::from_secs(5))
+            .unwrap()
+    );
+    assert!(slow.join().unwrap().ends_with("slow"));
+    let mut response = String::new();
+    queued.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert_eq!(header(&response, "Connection"), Some("close"));
+    assert!(response.ends_with("Hello, World"));
+}
+
+#[test]
+fn shutdown_timeout_abandons_stuck_handlers() {
+    let (release, stuck) = std::sync::mpsc::channel::<()>();
+    let stuck = std::sync::Mutex::new(stuck);
+    let server = spawn(hello_app().get("/stuck", move |_request, _response| {
+        // blocked on something other than the connection
+        let _ = stuck.lock().unwrap().recv_timeout(Duration::from_secs(30));
+        "done"
+    }));
+    let mut client = TcpStream::connect(server.local_addr()).unwrap();
+    client.write_all(b"GET /stuck HTTP/1.1\r\n\r\n").unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+
+    let started = std::time::Instant::now();
+    assert!(
+        !server
+            .shutdown_with_timeout(Duration::from_millis(200))
+            .unwrap()
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+    release.send(()).unwrap();
+}
+
+#[test]
+fn answers_malformed_requests_with_an_error_status() {
+    let server = spawn(hello_app());
+    let response = send(
+        server.local_addr(),
+        "POST /hello HTTP/1.1\r\nContent-Length: nope\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    let response = send(server.local_addr(), "GET /hello HTTP/2.0\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn parse_errors_can_be_customised() {
+    let server =
+        spawn(hello_app().on_parse_error(|err, response| {
+            response.set_body(&format!("{{\"error\":\"{err}\"}}"))
+        }));
+    let response = send(server.local_addr(), "GET\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(response.ends_with("{\"error\":\"invalid request line\"}"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn handler_panics_become_500_responses() {
+    let server = spawn(
+        hello_app()
+            .get("/boom", |_request, _response| -> Response {
+                panic!("kaboom")
+            })
+            .get("/custom/:id", |_request, _response| -> Response {
+                panic!("custom")
+            })
+            .on_panic(|request, handler_panic, response| {
+                if request.path.starts_with("/custom") {
+                    return response.set_body(&format!(
+                        "{} failed with {}",
+                        handler_panic.route, handler_panic.message
+                    ));
+                }
+                response.set_body("Internal Server Error")
+            }),
+    );
+    let addr = server.local_addr();
+
+    let response = send(addr, "GET /boom HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    assert!(response.ends_with("Internal Server Error"));
+
+    let response = send(addr, "GET /custom/1 HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.ends_with("/custom/:id failed with custom"));
+
+    // the worker survives and keeps serving
+    let response = send(addr, "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn middleware_wraps_route_handlers() {
+    let server = spawn(
+        hello_app()
+            .get("/admin", |request, response| {
+                let user = request.header("X-User").unwrap_or("nobody");
+                response.set_body(&format!("Hello, {user}")).set_status(200)
+            })
+            .wrap(|request, next| next.run(request).add_header("X-Served-By", "test"))
+            .wrap_route(
+                RequestMethod::Get,
+                "/admin",
+                |mut request, next| match request.header("Authorization") {
+                    Some("Bearer secret") => {
+                        request.headers.insert("X-User".into(), "admin".into());
+                        next.run(request)
+                    }
+                    _ => Response::new().set_status(401),
+                },
+            ),
+    );
+    let addr = server.local_addr();
+
+    let response = send(addr, "GET /admin HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 401 "));
+    assert!(response.contains("X-Served-By: test\r\n"));
+
+    let response = send(
+        addr,
+        "GET /admin HTTP/1.1\r\nAuthorization: Bearer secret\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.ends_with("Hello, admin"));
+
+    // global middleware also sees requests no route matched
+    let response = send(addr, "GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(response.contains("X-Served-By: test\r\n"));
+
+    // gzip is middleware too and still applies
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /hello HTTP/1.1\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).unwrap();
+    assert!(String::from_utf8_lossy(&response).contains("Content-Encoding: gzip\r\n"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn handlers_share_app_state_and_request_extensions() {
+    struct Visits(AtomicUsize);
+    struct CurrentUser(String);
+
+    let server = spawn(
+        App::new()
+            .with_state(Visits(AtomicUsize::new(0)))
+            .get("/visit", |request, response| {
+                let visits = request.state::<Visits>().unwrap();
+                let count = visits.0.fetch_add(1, Ordering::SeqCst) + 1;
+                let user = request
+                    .state::<CurrentUser>()
+                    .map_or("anonymous", |user| user.0.as_str());
+                response
+                    .set_body(&format!("{user} is visit {count}"))
+                    .set_status(200)
+            })
+            .wrap(|mut request, next| {
+                if let Some(name) = request.header("X-Name") {
+                    let user = CurrentUser(name.to_string());
+                    request.extensions.insert(user);
+                }
+                next.run(request)
+            }),
+    );
+    let addr = server.local_addr();
+
+    let response = send(addr, "GET /visit HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.ends_with("anonymous is visit 1"));
+    let response = send(
+        addr,
+        "GET /visit HTTP/1.1\r\nX-Name: ada\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.ends_with("ada is visit 2"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn handlers_can_return_anything_that_converts_into_a_response() {
+    let server = spawn(
+        App::new()
+            .get("/text", |_request, _response| "plain text")
+            .post("/items", |request, _response| {
+                (201, format!("created {} bytes", request.body.len()))
+            })
+            .get("/items/:id", |request, _response| {
+                let id = request.path_params.get("id").unwrap();
+                id.parse::<u32>()
+                    .map(|id| (id == 1).then(|| format!("item {id}")))
+                    .map_err(|_| (400, "ids are numbers"))
+            }),
+    );
+    let addr = server.local_addr();
+    let get = |path: &str| {
+        send(
+            addr,
+            &format!("GET {path} HTTP/1.1\r\nConnection: close\r\n\r\n"),
+        )
+    };
+
+    let response = get("/text");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("plain text"));
+    let response = send(
+        addr,
+        "POST /items HTTP/1.1\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc",
+    );
+    assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+    assert!(response.ends_with("created 3 bytes"));
+    assert!(get("/items/1").ends_with("item 1"));
+    assert!(get("/items/2").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    let response = get("/items/x");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(response.ends_with("ids are numbers"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn wrong_methods_get_405_with_the_allowed_ones() {
+    let server = spawn(
+        hello_app()
+            .post("/hello", |_request, _response| "posted")
+            .delete("/users/:id", |_request, _response| "deleted")
+            .get("/users/:id", |_request, _response| "user"),
+    );
+    let addr = server.local_addr();
+
+    let response = send(
+        addr,
+        "PUT /hello HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    assert!(response.contains("Allow: GET, HEAD, OPTIONS, POST\r\n"));
+
+    let response = send(
+        addr,
+        "POST /users/7 HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.contains("Allow: DELETE, GET, HEAD, OPTIONS\r\n"));
+
+    // paths no method has a route for are still 404
+    let response = send(
+        addr,
+        "POST /nothing HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn answers_head_and_options_without_dedicated_handlers() {
+    let server = spawn(
+        hello_app()
+            .post("/hello", |_request, _response| "posted")
+            .get("/custom", |_request, _response| "custom")
+            .options("/custom", |_request, response| {
+                response.set_status(200).add_header("Allow", "GET")
+            }),
+    );
+    let addr = server.local_addr();
+
+    // the body is left out but the next response on the connection still lines up
+    let response = send(
+        addr,
+        "HEAD /hello HTTP/1.1\r\n\r\nGET /hello HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    let (head, get) = response.split_at(response.rfind("HTTP/1.1 200 OK").unwrap());
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(head.contains("Content-Length: 12\r\n"));
+    assert!(head.ends_with("\r\n\r\n"));
+    assert!(get.ends_with("\r\n\r\nHello, World"));
+
+    let response = send(addr, "OPTIONS /hello HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert!(response.contains("Allow: GET, HEAD, OPTIONS, POST\r\n"));
+
+    // explicit handlers take precedence
+    let response = send(
+        addr,
+        "OPTIONS /custom HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Allow: GET\r\n"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn routes_patch_and_extension_methods() {
+    let server = spawn(
+        hello_app()
+            .patch("/hello", |_request, _response| "patched")
+            .route(
+                RequestMethod::from("PROPFIND"),
+                "/files/:name",
+                |request, _response| format!("{} {}", request.method, request.path_params["name"]),
+            ),
+    );
+    let addr = server.local_addr();
+
+    let response = send(
+        addr,
+        "PATCH /hello HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.ends_with("\r\n\r\npatched"));
+
+    let response = send(
+        addr,
+        "PROPFIND /files/notes HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.ends_with("\r\n\r\nPROPFIND notes"));
+
+    let response = send(
+        addr,
+        "MKCOL /files/notes HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    assert!(response.contains("Allow: OPTIONS, PROPFIND\r\n"));
+
+    // methods have to be tokens
+    let response = send(addr, "GET{} /hello HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn scopes_group_routes_under_a_prefix_with_their_own_middleware() {
+    let tag = |name: &'static str| {
+        move |request, next: simple_http_server::middleware::Next| {
+            let response = next.run(request);
+            let tags = match response.get_header("X-Tags") {
+                Some(tags) => format!("{name} {tags}"),
+                None => name.to_string(),
+            };
+            response.add_header("X-Tags", &tags)
+        }
+    };
+    let mut admin = Router::new();
+    admin.route(RequestMethod::Get, "/", |_request, _response| "admin");
+    admin.wrap(tag("admin"));
+    let server = spawn(
+        hello_app()
+            .scope("/api/v1/", |scope| {
+                scope
+                    .wrap(tag("api"))
+                    .get("/users/:id", |request, _response| {
+                        format!("user {}", request.path_params["id"])
+                    })
+                    .scope("/orders", |scope| {
+                        scope
+                            .wrap(tag("orders"))
+                            .post("/", |_request, _response| "ordered")
+                    })
+            })
+            .nest("/admin", admin),
+    );
+    let addr = server.local_addr();
+
+    let response = send(
+        addr,
+        "GET /api/v1/users/7 HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.contains("X-Tags: api\r\n"));
+    assert!(response.ends_with("\r\n\r\nuser 7"));
+
+    let response = send(
+        addr,
+        "POST /api/v1/orders HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.contains("X-Tags: api orders\r\n"));
+    assert!(response.ends_with("\r\n\r\nordered"));
+
+    let response = send(addr, "GET /admin HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.contains("X-Tags: admin\r\n"));
+
+    // routes outside of a scope don't run its middleware
+    let response = send(addr, "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(!response.contains("X-Tags"));
+    let response = send(addr, "GET /users/7 HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn scopes_keep_literal_routes_ahead_of_params() {
+    let fruit = |request: &simple_http_server::request::Request, _response| {
+        format!("fruit {}", request.path_params["fruit"])
+    };
+    let server = spawn(App::new().scope("/api", |scope| {
+        // enough pairs that a random insertion order would hit a param first
+        [
+            "apple", "banana", "cherry", "grape", "lemon", "mango", "peach", "plum",
+        ]
+        .into_iter()
+        .fold(scope, |scope, name| {
+            scope
+                .get(&format!("/{name}/special"), move |_request, _response| {
+                    format!("special {name}")
+                })
+                .get(&format!("/{name}/:fruit"), fruit)
+        })
+    }));
+    let addr = server.local_addr();
+    for name in ["apple", "plum"] {
+        let response = send(
+            addr,
+            &format!("GET /api/{name}/special HTTP/1.1\r\nConnection: close\r\n\r\n"),
+        );
+        assert!(response.ends_with(&format!("\r\n\r\nspecial {name}")));
+        let response = send(
+            addr,
+            &format!("GET /api/{name}/kiwi HTTP/1.1\r\nConnection: close\r\n\r\n"),
+        );
+        assert!(response.ends_with("\r\n\r\nfruit kiwi"));
+    }
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn path_params_keep_their_case() {
+    let file = |request: &simple_http_server::request::Request, _response| {
+        request.path_params["fileName"].clone()
+    };
+    let server = spawn(hello_app().get("/files/:fileName", file));
+    let addr = server.local_addr();
+    let response = send(
+        addr,
+        "GET /FILES/Report.PDF HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.ends_with("\r\n\r\nReport.PDF"));
+    server.shutdown().unwrap();
+
+    let server = spawn(
+        hello_app()
+            .get("/files/:fileName", file)
+            .set_case_sensitive_routes(true),
+    );
+    let addr = server.local_addr();
+    let response = send(
+        addr,
+        "GET /files/Report.PDF HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.ends_with("\r\n\r\nReport.PDF"));
+    let response = send(
+        addr,
+        "GET /FILES/Report.PDF HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn case_sensitivity_can_be_changed_with_priority_routes() {
+    let fruit = |request: &simple_http_server::request::Request, _response| {
+        format!("fruit {}", request.path_params["fruit"])
+    };
+    let server = spawn(
+        App::new()
+            .get("/fruit/apple", |_request, _response| "apple")
+            .get("/fruit/:fruit", fruit)
+            .set_case_sensitive_routes(true),
+    );
+    let addr = server.local_addr();
+    let response = send(
+        addr,
+        "GET /fruit/apple HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.ends_with("\r\n\r\napple"));
+    let response = send(
+        addr,
+        "GET /fruit/kiwi HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.ends_with("\r\n\r\nfruit kiwi"));
+    server.shutdown().unwrap();
+
+    // only differing in case from a literal route doesn't make a param route collide
+    let mut router = Router::new();
+    router.set_case_sensitive(true);
+    router.route(RequestMethod::Get, "/fruit/:fruit", |_request, response| {
+        response
+    });
+    router.route(RequestMethod::Get, "/Fruit/apple", |_request, response| {
+        response
+    });
+    router.set_case_sensitive(false);
+}
+
+#[test]
+#[should_panic(expected = "A handler has already been defined for this url pattern")]
+fn routes_differing_only_in_case_collide_once_case_is_ignored() {
+    let mut router = Router::new();
+    router.set_case_sensitive(true);
+    router.route(RequestMethod::Get, "/Users", |_request, response| response);
+    router.route(RequestMethod::Get, "/users", |_request, response| response);
+    router.set_case_sensitive(false);
+}
+
+#[test]
+fn responses_without_content_leave_the_connection_in_sync() {
+    let server = spawn(hello_app().delete("/hello", |_request, _response| (204, "deleted")));
+    let response = send(
+        server.local_addr(),
+        "DELETE /hello HTTP/1.1\r\n\r\nGET /hello HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    let (deleted, hello) = response.split_at(response.find("HTTP/1.1 200 OK").unwrap());
+    assert!(deleted.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert!(deleted.ends_with("\r\n\r\n"));
+    assert!(!deleted.contains("Content-Length") && !deleted.contains("deleted"));
+    assert!(hello.ends_with("\r\n\r\nHello, World"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn streams_bodies_of_unknown_length() {
+    let server = spawn(hello_app().get("/csv", |_request, response| {
+        let rows = (1..=3).map(|id| format!("{id},row {id}\n").into_bytes());
+        response
+            .set_body_chunks(rows)
+            .set_trailers(|| vec![("X-Rows".to_string(), "3".to_string())])
+            .set_status(200)
+    }));
+    let addr = server.local_addr();
+
+    // chunked bodies keep the connection usable for the next request
+    let response = send(
+        addr,
+        "GET /csv HTTP/1.1\r\n\r\nGET /hello HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+    assert!(response.contains(
+        "\r\n\r\n8\r\n1,row 1\n\r\n8\r\n2,row 2\n\r\n8\r\n3,row 3\n\r\n0\r\nX-Rows: 3\r\n\r\nHTTP/1.1 200 OK"
+    ));
+    assert!(response.ends_with("Hello, World"));
+
+    // HTTP/1.0 clients get the raw body and the connection is closed after it
+    let response = send(addr, "GET /csv HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+    assert!(!response.contains("Transfer-Encoding"));
+    assert!(!response.contains("Content-Length"));
+    assert!(response.ends_with("\r\n\r\n1,row 1\n2,row 2\n3,row 3\n"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn streams_server_sent_events() {
+    let server = spawn(App::new().get("/events", |request, response| {
+        response.sse(request, |events| {
+            let last = events
+                .last_event_id()
+                .unwrap_or("0")
+                .parse::<u32>()
+                .unwrap();
+            for id in last + 1..=last + 2 {
+                let event = Event::new(&format!("tick {id}")).set_id(&id.to_string());
+                if events.send(event).is_err() {
+                    return;
+                }
+            }
+        })
+    }));
+    let response = send(
+        server.local_addr(),
+        "GET /events HTTP/1.1\r\nLast-Event-ID: 5\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/event-stream\r\n"));
+    assert!(response.contains("id: 6\ndata: tick 6\n\n"));
+    assert!(response.contains("id: 7\ndata: tick 7\n\n"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn long_lived_connections_leave_the_workers_free() {
+    let app = hello_app()
+        .get("/events", |request, response| {
+            response.sse(request, |events| {
+                while events.send(Event::new("tick")).is_ok() {
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+            })
+        })
+        .ws(
+            "/echo",
+            |_request, mut socket| while socket.recv().is_ok() {},
+        )
+        .set_workers(1);
+    let server = spawn(app);
+    let addr = server.local_addr();
+
+    let open = |raw_request: &str, expected: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(raw_request.as_bytes()).unwrap();
+        let mut buffer = [0; 1024];
+        let read = stream.read(&mut buffer).unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..read]).starts_with(expected));
+        stream
+    };
+    let events = (0..2)
+        .map(|_| open("GET /events HTTP/1.1\r\n\r\n", "HTTP/1.1 200 OK\r\n"))
+        .collect::<Vec<_>>();
+    let socket = open(
+        "GET /echo HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        "HTTP/1.1 101 Switching Protocols\r\n",
+    );
+
+    // the only worker has to be free again for this one
+    let response = send(addr, "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    drop((events, socket));
+    server.shutdown().unwrap();
+}
+
+// A single masked client frame with a payload shorter than 126 bytes.
+fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [1, 2, 3, 4];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4]),
+    );
+    frame
+}
+
+#[test]
+fn upgrades_websocket_connections() {
+    let server = spawn(hello_app().ws("/echo/:room", |request, mut socket| {
+        let room = request.path_params.get("room").unwrap().clone();
+        while let Ok(message) = socket.recv() {
+            match message {
+                Message::Text(text) => {
+                    let _ = socket.send_text(&format!("{room}: {text}"));
+                }
+                Message::Close(_) => return,
+                _ => {}
+            }
+        }
+    }));
+    let addr = server.local_addr();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut request = b"GET /echo/lobby HTTP/1.1\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n".to_vec();
+    // a frame sent right behind the handshake must not get lost
+    request.extend(client_frame(0x1, b"hi"));
+    stream.write_all(&request).unwrap();
+    stream
+        .write_all(&client_frame(0x8, &1000u16.to_be_bytes()))
+        .unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.ends_with(b"\r\n\r\n\x81\x09lobby: hi\x88\x02\x03\xe8"));
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+    let response = send(
+        addr,
+        "GET /echo/lobby HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+    server.shutdown().unwrap();
+}
+
+fn h2_frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.extend([kind, flags]);
+    frame.extend(stream_id.to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+// A header block of literals without indexing or Huffman coding.
+fn h2_headers(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = vec![];
+    for (name, value) in fields {
+        block.push(0);
+        block.push(name.len() as u8);
+        block.extend(name.as_bytes());
+        block.push(value.len() as u8);
+        block.extend(value.as_bytes());
+    }
+    block
+}
+
+// Reads frames until `count` streams ended, returning the first byte of the response
+// header block and the body of each stream.
+fn h2_responses(stream: &mut TcpStream, count: usize) -> HashMap<u32, (u8, Vec<u8>)> {
+    let mut responses = HashMap::new();
+    let mut ended = 0;
+    while ended < count {
+        let mut header = [0; 9];
+        stream.read_exact(&mut header).unwrap();
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let stream_id = u32::from_be_bytes(header[5..9].try_into().unwrap());
+        let mut payload = vec![0; length];
+        stream.read_exact(&mut payload).unwrap();
+        match header[3] {
+            0x0 => responses
+                .entry(stream_id)
+                .or_insert((0, vec![]))
+                .1
+                .extend(payload),
+            0x1 => {
+                responses.entry(stream_id).or_insert((payload[0], vec![]));
+            }
+            _ => continue,
+        }
+        if header[4] & 0x1 != 0 {
+            ended += 1;
+        }
+    }
+    responses
+}
+
+fn echo_app() -> App {
+    hello_app().post("/echo", |request, response| {
+        response.set_body_bytes(&request.body).set_status(200)
+    })
+}
+
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+#[test]
+fn serves_http2_with_prior_knowledge() {
+    let server = spawn(echo_app());
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let request = |method, path| {
+        h2_headers(&[
+            (":method", method),
+            (":scheme", "http"),
+            (":path", path),
+            (":authority", "localhost"),
+        ])
+    };
+    let mut frames = H2_PREFACE.to_vec();
+    frames.extend(h2_frame(0x4, 0, 0, &[]));
+    frames.extend(h2_frame(0x1, 0x5, 1, &request("GET", "/hello")));
+    frames.extend(h2_frame(0x1, 0x4, 3, &request("POST", "/echo")));
+    frames.extend(h2_frame(0x1, 0x5, 5, &request("GET", "/missing")));
+    frames.extend(h2_frame(0x1, 0x5, 7, &request("HEAD", "/hello")));
+    frames.extend(h2_frame(0x0, 0, 3, b"ping "));
+    frames.extend(h2_frame(0x0, 0x1, 3, b"pong"));
+    stream.write_all(&frames).unwrap();
+
+    let responses = h2_responses(&mut stream, 4);
+    // `:status` from the static table: 0x88 is 200, 0x8d is 404
+    assert_eq!(responses[&1], (0x88, b"Hello, World".to_vec()));
+    assert_eq!(responses[&3], (0x88, b"ping pong".to_vec()));
+    assert_eq!(responses[&5].0, 0x8d);
+    assert_eq!(responses[&7], (0x88, vec![]));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn reset_http2_streams_count_until_their_handlers_return() {
+    let server = spawn(hello_app().get("/slow", |_request, _response| {
+        std::thread::sleep(Duration::from_millis(500));
+        "slow"
+    }));
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let request = |path| {
+        h2_headers(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", path),
+            (":authority", "localhost"),
+        ])
+    };
+    let mut frames = H2_PREFACE.to_vec();
+    frames.extend(h2_frame(0x4, 0, 0, &[]));
+    // opening and cancelling streams right away must not start more handlers than
+    // streams could be open at once
+    for stream_id in (1..=15).step_by(2) {
+        frames.extend(h2_frame(0x1, 0x5, stream_id, &request("/slow")));
+        frames.extend(h2_frame(0x3, 0, stream_id, &8u32.to_be_bytes()));
+    }
+    frames.extend(h2_frame(0x1, 0x5, 17, &request("/hello")));
+    stream.write_all(&frames).unwrap();
+
+    loop {
+        let mut header = [0; 9];
+        stream.read_exact(&mut header).unwrap();
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let stream_id = u32::from_be_bytes(header[5..9].try_into().unwrap());
+        let mut payload = vec![0; length];
+        stream.read_exact(&mut payload).unwrap();
+        if stream_id == 17 {
+            // RST_STREAM with REFUSED_STREAM
+            assert_eq!((header[3], payload), (0x3, 7u32.to_be_bytes().to_vec()));
+            break;
+        }
+    }
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn http2_requests_that_stop_short_time_out() {
+    let server = spawn(echo_app().set_keep_alive_timeout(Duration::from_millis(200)));
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut frames = H2_PREFACE.to_vec();
+    frames.extend(h2_frame(0x4, 0, 0, &[]));
+    // the body announced by leaving the stream open never comes
+    let request = h2_headers(&[
+        (":method", "POST"),
+        (":scheme", "http"),
+        (":path", "/echo"),
+        (":authority", "localhost"),
+    ]);
+    frames.extend(h2_frame(0x1, 0x4, 1, &request));
+    stream.write_all(&frames).unwrap();
+
+    let mut received = vec![];
+    stream.read_to_end(&mut received).unwrap();
+    let mut frames = received.as_slice();
+    let mut kinds = vec![];
+    while frames.len() >= 9 {
+        let length = u32::from_be_bytes([0, frames[0], frames[1], frames[2]]) as usize;
+        kinds.push(frames[3]);
+        frames = &frames[9 + length..];
+    }
+    assert_eq!(kinds.last(), Some(&0x7));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn http2_streams_never_switch_protocols() {
+    let server = spawn(
+        hello_app()
+            .ws("/socket", |_request, _socket| {})
+            .get("/switch", |_request, response| response.set_status(101)),
+    );
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let request = |path| {
+        h2_headers(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", path),
+            (":authority", "localhost"),
+            ("sec-websocket-version", "13"),
+            ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ])
+    };
+    let mut frames = H2_PREFACE.to_vec();
+    frames.extend(h2_frame(0x4, 0, 0, &[]));
+    frames.extend(h2_frame(0x1, 0x5, 1, &request("/socket")));
+    frames.extend(h2_frame(0x1, 0x5, 3, &request("/switch")));
+    stream.write_all(&frames).unwrap();
+
+    let responses = h2_responses(&mut stream, 2);
+    // neither status is in the static table, the bodies tell them apart
+    assert_eq!(responses[&1].0, 0x08);
+    assert_eq!(
+        responses[&1].1,
+        b"This endpoint only accepts WebSocket connections".to_vec()
+    );
+    assert_eq!(responses[&3].0, 0x08);
+    assert_eq!(
+        responses[&3].1,
+        b"Switching protocols requires HTTP/1.1".to_vec()
+    );
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn waits_for_the_whole_preface_before_switching_to_http2() {
+    let server = spawn(echo_app());
+    let connect = || {
+        let stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.set_nodelay(true).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    };
+
+    // a POST whose first segment only holds a byte shared with the preface
+    let mut stream = connect();
+    stream.write_all(b"P").unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    stream
+        .write_all(b"OST /echo HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\nping")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nping"));
+
+    let mut stream = connect();
+    stream.write_all(&H2_PREFACE[..9]).unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    let mut frames = H2_PREFACE[9..].to_vec();
+    frames.extend(h2_frame(0x4, 0, 0, &[]));
+    let request = h2_headers(&[(":method", "GET"), (":scheme", "http"), (":path", "/hello")]);
+    frames.extend(h2_frame(0x1, 0x5, 1, &request));
+    stream.write_all(&frames).unwrap();
+    let responses = h2_responses(&mut stream, 1);
+    assert_eq!(responses[&1], (0x88, b"Hello, World".to_vec()));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn upgrades_to_http2_over_cleartext() {
+    let server = spawn(echo_app());
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk\r\n\r\n")
+        .unwrap();
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+    assert!(response.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+
+    let mut frames = H2_PREFACE.to_vec();
+    frames.extend(h2_frame(0x4, 0, 0, &[]));
+    stream.write_all(&frames).unwrap();
+    // the upgrade request is answered on stream 1
+    let responses = h2_responses(&mut stream, 1);
+    assert_eq!(responses[&1], (0x88, b"Hello, World".to_vec()));
+    server.shutdown().unwrap();
+}
+
+// A directory of its own under the system temp dir, removed again when dropped so a
+// failing test doesn't leave it behind.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!(
+            "simple-http-server-{name}-{}-{nanos}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// The value of the first `name` header in a raw response.
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    let head = response.split_once("\r\n\r\n")?.0;
+    head.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(": "))
+        .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+// A static folder mounted at `/static`, served from `public` inside a fresh temporary
+// directory so tests can also place files next to it.
+struct StaticFolder {
+    server: Server,
+    dir: TempDir,
+}
+
+impl StaticFolder {
+    fn new(name: &str, files: &[(&str, &[u8])]) -> Self {
+        let dir = TempDir::new(name);
+        let root = dir.0.join("public");
+        std::fs::create_dir_all(&root).unwrap();
+        for (path, content) in files {
+            std::fs::write(root.join(path), content).unwrap();
+        }
+        let server = spawn(App::new().static_folder("/static", &root));
+        Self { server, dir }
+    }
+
+    // `extra_headers` are sent as is, each line ending with a CRLF.
+    fn get(&self, path: &str, extra_headers: &str) -> String {
+        send(
+            self.server.local_addr(),
+            &format!("GET {path} HTTP/1.1\r\n{extra_headers}Connection: close\r\n\r\n"),
+        )
+    }
+
+    fn shutdown(self) {
+        self.server.shutdown().unwrap();
+    }
+}
+
+#[test]
+fn static_folder_does_not_escape_its_root() {
+    let folder = StaticFolder::new("static", &[("index.html", b"<h1>index</h1>")]);
+    let base = &folder.dir.0;
+    std::fs::write(base.join("secret.txt"), "secret").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(base.join("secret.txt"), base.join("public/link.txt")).unwrap();
+
+    assert!(folder.get("/static/", "").ends_with("<h1>index</h1>"));
+    for path in [
+        "/static/../secret.txt",
+        "/static/%2e%2e/secret.txt",
+        "/static/..%2fsecret.txt",
+        "/static/%2e%2e%2fsecret.txt",
+        "/static/index.html%00.txt",
+    ] {
+        let response = folder.get(path, "");
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{path}");
+        assert!(!response.contains("secret"), "{path}");
+    }
+    #[cfg(unix)]
+    assert!(
+        folder
+            .get("/static/link.txt", "")
+            .starts_with("HTTP/1.1 403 Forbidden\r\n")
+    );
+
+    folder.shutdown();
+}
+
+#[test]
+fn static_files_answer_range_requests() {
+    let folder = StaticFolder::new("ranges", &[("digits.txt", b"0123456789")]);
+    let get = |range: &str| folder.get("/static/digits.txt", &format!("Range: {range}\r\n"));
+
+    let response = get("bytes=2-5");
+    assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    assert_eq!(header(&response, "Content-Range"), Some("bytes 2-5/10"));
+    assert_eq!(header(&response, "Content-Length"), Some("4"));
+    assert!(response.ends_with("\r\n\r\n2345"));
+
+    let response = get("bytes=-3");
+    assert_eq!(header(&response, "Content-Range"), Some("bytes 7-9/10"));
+    assert!(response.ends_with("\r\n\r\n789"));
+
+    let response = get("bytes=20-");
+    assert!(response.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+    assert_eq!(header(&response, "Content-Range"), Some("bytes */10"));
+
+    let response = get("bytes=0-1,8-9");
+    assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    let boundary = header(&response, "Content-Type")
+        .and_then(|value| value.strip_prefix("multipart/byteranges; boundary="))
+        .unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1;
+    let part = |range: &str, content: &str| {
+        format!(
+            "\r\n--{boundary}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes {range}/10\r\n\r\n{content}"
+        )
+    };
+    let expected = format!(
+        "{}{}\r\n--{boundary}--\r\n",
+        part("0-1", "01"),
+        part("8-9", "89")
+    );
+    assert_eq!(body, expected);
+    assert_eq!(
+        header(&response, "Content-Length"),
+        Some(body.len().to_string().as_str())
+    );
+
+    folder.shutdown();
+}
+
+#[test]
+fn static_files_answer_conditional_requests() {
+    let folder = StaticFolder::new("conditional", &[("page.html", b"<h1>page</h1>")]);
+    let get = |condition: &str| folder.get("/static/page.html", condition);
+
+    let response = get("");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    let etag = header(&response, "ETag").unwrap().to_string();
+    let last_modified = header(&response, "Last-Modified").unwrap().to_string();
+
+    let response = get(&format!("If-None-Match: \"other\", {etag}\r\n"));
+    assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+    assert_eq!(header(&response, "ETag"), Some(etag.as_str()));
+    assert!(response.ends_with("\r\n\r\n"));
+    let response = get(&format!("If-Modified-Since: {last_modified}\r\n"));
+    assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+    let response = get("If-None-Match: \"other\"\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("<h1>page</h1>"));
+
+    let response = get(&format!("If-Match: {etag}\r\n"));
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    let response = get("If-Match: \"other\"\r\n");
+    assert!(response.starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+    assert!(!response.contains("<h1>page</h1>"));
+
+    folder.shutdown();
+}
+
+#[test]
+fn static_files_stream_large_files_with_their_length() {
+    // well above the size static files are buffered up to
+    let content = (0..3 * 1024 * 1024 + 7)
+        .map(|i| b'a' + (i % 26) as u8)
+        .collect::<Vec<u8>>();
+    let folder = StaticFolder::new("large", &[("large.bin", &content)]);
+
+    let response = folder.get("/static/large.bin", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert_eq!(
+        header(&response, "Content-Length"),
+        Some(content.len().to_string().as_str())
+    );
+    assert_eq!(header(&response, "Transfer-Encoding"), None);
+    assert!(response.split_once("\r\n\r\n").unwrap().1.as_bytes() == content);
+
+    folder.shutdown();
+}
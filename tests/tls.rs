@@ -0,0 +1,253 @@
+#![cfg(feature = "tls")]
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme, StreamOwned,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+};
+use simple_http_server::App;
+
+// Accepts any certificate and remembers which one the server presented.
+#[derive(Debug)]
+struct RecordingVerifier {
+    provider: CryptoProvider,
+    presented: Mutex<Option<CertificateDer<'static>>>,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        *self.presented.lock().unwrap() = Some(end_entity.clone().into_owned());
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+fn certificate(path: &Path) -> CertificateDer<'static> {
+    CertificateDer::from_pem_file(path).unwrap()
+}
+
+fn connect(
+    addr: SocketAddr,
+    server_name: &str,
+    alpn_protocols: &[&[u8]],
+) -> (
+    StreamOwned<ClientConnection, TcpStream>,
+    Arc<RecordingVerifier>,
+) {
+    let verifier = Arc::new(RecordingVerifier {
+        provider: ring::default_provider(),
+        presented: Mutex::new(None),
+    });
+    let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .dangerous()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_no_client_auth();
+    config.alpn_protocols = alpn_protocols
+        .iter()
+        .map(|protocol| protocol.to_vec())
+        .collect();
+    let connection = ClientConnection::new(
+        Arc::new(config),
+        ServerName::try_from(server_name.to_string()).unwrap(),
+    )
+    .unwrap();
+    let socket = TcpStream::connect(addr).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    (StreamOwned::new(connection, socket), verifier)
+}
+
+// Sends a request over TLS, returning the response and the certificate presented.
+fn send(
+    addr: SocketAddr,
+    server_name: &str,
+    raw_request: &str,
+) -> (String, CertificateDer<'static>) {
+    let (mut stream, verifier) = connect(addr, server_name, &[]);
+    stream.write_all(raw_request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let presented = verifier.presented.lock().unwrap().take().unwrap();
+    (response, presented)
+}
+
+fn hello_app() -> App {
+    App::new()
+        .set_listen_ip("127.0.0.1")
+        .get("/hello", |_request, response| {
+            response.set_body("Hello, World").set_status(200)
+        })
+}
+
+const REQUEST: &str = "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+#[test]
+fn serves_requests_over_tls_with_sni() {
+    let server = hello_app()
+        .set_tls(&fixture("localhost.crt"), &fixture("localhost.key"))
+        .add_tls_certificate(
+            "*.test",
+            &fixture("example.test.crt"),
+            &fixture("example.test.key"),
+        )
+        .spawn()
+        .unwrap();
+    let addr = server.local_addr();
+
+    let (response, presented) = send(addr, "localhost", REQUEST);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("Hello, World"));
+    assert_eq!(presented, certificate(&fixture("localhost.crt")));
+
+    let (response, presented) = send(addr, "example.test", REQUEST);
+    assert!(response.ends_with("Hello, World"));
+    assert_eq!(presented, certificate(&fixture("example.test.crt")));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn reloads_certificates_without_a_restart() {
+    let dir = std::env::temp_dir().join(format!("simple-http-server-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("server.crt"), dir.join("server.key"));
+    fs::copy(fixture("localhost.crt"), &cert).unwrap();
+    fs::copy(fixture("localhost.key"), &key).unwrap();
+
+    let server = hello_app().set_tls(&cert, &key).spawn().unwrap();
+    let addr = server.local_addr();
+    let (_, presented) = send(addr, "localhost", REQUEST);
+    assert_eq!(presented, certificate(&fixture("localhost.crt")));
+
+    // a broken file is reported and the current certificate stays in use
+    fs::write(&cert, "not a certificate").unwrap();
+    assert!(server.handle().reload_tls().is_err());
+    let (_, presented) = send(addr, "localhost", REQUEST);
+    assert_eq!(presented, certificate(&fixture("localhost.crt")));
+
+    fs::copy(fixture("example.test.crt"), &cert).unwrap();
+    fs::copy(fixture("example.test.key"), &key).unwrap();
+    server.handle().reload_tls().unwrap();
+    let (response, presented) = send(addr, "localhost", REQUEST);
+    assert!(response.ends_with("Hello, World"));
+    assert_eq!(presented, certificate(&fixture("example.test.crt")));
+
+    server.shutdown().unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn negotiates_http2_with_alpn() {
+    let server = hello_app()
+        .set_tls(&fixture("localhost.crt"), &fixture("localhost.key"))
+        .spawn()
+        .unwrap();
+    let addr = server.local_addr();
+
+    let (mut stream, _) = connect(addr, "localhost", &[b"h2", b"http/1.1"]);
+    let mut frames = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+    // an empty SETTINGS frame, then GET /hello as literal header fields on stream 1
+    frames.extend(b"\x00\x00\x00\x04\x00\x00\x00\x00\x00");
+    let mut block = vec![];
+    for (name, value) in [
+        (":method", "GET"),
+        (":scheme", "https"),
+        (":path", "/hello"),
+    ] {
+        block.extend([0, name.len() as u8]);
+        block.extend(name.as_bytes());
+        block.push(value.len() as u8);
+        block.extend(value.as_bytes());
+    }
+    frames.extend(&(block.len() as u32).to_be_bytes()[1..]);
+    frames.extend(b"\x01\x05\x00\x00\x00\x01");
+    frames.extend(block);
+    stream.write_all(&frames).unwrap();
+    assert_eq!(stream.conn.alpn_protocol(), Some(&b"h2"[..]));
+
+    let mut body = vec![];
+    loop {
+        let mut header = [0; 9];
+        stream.read_exact(&mut header).unwrap();
+        let mut payload =
+            vec![0; u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize];
+        stream.read_exact(&mut payload).unwrap();
+        // DATA on stream 1
+        if header[3] == 0x0 && header[8] == 1 {
+            body.extend(payload);
+            if header[4] & 0x1 != 0 {
+                break;
+            }
+        }
+    }
+    assert_eq!(body, b"Hello, World");
+
+    // clients without ALPN keep getting HTTP/1.1
+    let (response, _) = send(addr, "localhost", REQUEST);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn fails_to_start_with_missing_certificates() {
+    let app = hello_app().set_tls(&fixture("missing.crt"), &fixture("missing.key"));
+    assert!(app.spawn().is_err());
+}